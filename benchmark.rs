@@ -2,11 +2,45 @@ use std::fs::File;
 use std::io::{self, BufRead};
 use std::path::Path;
 
-mod position;
-mod solver;
-use crate::solver::{Outcome, Solver};
+use connect_four::{Outcome, Position, Solver, CONNECT_FOUR, DEFAULT_TABLE_SIZE};
 
 fn main() {
+	let mut table_size = DEFAULT_TABLE_SIZE;
+	let mut exact = false;
+	let mut threads = 1;
+	let mut table_file = None;
+	let mut args = std::env::args().skip(1);
+	while let Some(arg) = args.next() {
+		match arg.as_str() {
+			"--tt-size" => match args.next().as_deref().map(parse_size) {
+				Some(Ok(size)) => table_size = size,
+				Some(Err(err)) => return println!("{}", err),
+				None => return println!("--tt-size expects a size, e.g. 64MB"),
+			},
+			"--exact" => exact = true,
+			"--threads" => match args.next().map(|n| n.parse::<usize>()) {
+				Some(Ok(n)) if n > 0 => threads = n,
+				_ => return println!("--threads expects a positive number of threads"),
+			},
+			"--tt-file" => match args.next() {
+				Some(path) => table_file = Some(path),
+				None => return println!("--tt-file expects a path"),
+			},
+			_ => return println!(
+				"Unknown argument {}.\nUsage: benchmark [--tt-size SIZE] [--exact] [--threads N] [--tt-file FILE]",
+				arg
+			),
+		}
+	}
+	let mut solver = Solver::with_table_size(table_size);
+	if let Some(path) = &table_file {
+		match solver.load_table(path, CONNECT_FOUR) {
+			Ok(()) => println!("Loaded transposition table {}.", path),
+			Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+			Err(err) => return println!("Could not load {}: {}", path, err),
+		}
+	}
+
 	for test in [
 		"end_easy",
 		"middle_easy",
@@ -18,9 +52,13 @@ fn main() {
 		for strongly in [false, true] {
 			if let Err(err) =
 				test_file(
+					&mut solver,
 					test,
 					format!("{}/data/{}", env!("CARGO_MANIFEST_DIR"), test),
 					strongly,
+					exact,
+					threads,
+					table_file.is_none(),
 				)
 			{
 				println!("{}", err);
@@ -29,11 +67,54 @@ fn main() {
 		}
 	}
 
+	if let Some(path) = &table_file {
+		if let Err(err) = solver.save_table(path) {
+			println!("Could not save {}: {}", path, err);
+		}
+	}
+
 }
 
+// Parses sizes such as `4096`, `512KB` or `64MB` into a number of bytes.
+fn parse_size(size: &str) -> Result<usize, String> {
+	let digits = size.find(|c: char| !c.is_ascii_digit()).unwrap_or(size.len());
+	let unit = match size[digits..].to_ascii_uppercase().as_str() {
+		"" | "B" => 1,
+		"K" | "KB" => 1 << 10,
+		"M" | "MB" => 1 << 20,
+		"G" | "GB" => 1 << 30,
+		_ => return Err(format!("Invalid size {}, expected e.g. 64MB.", size)),
+	};
+	size[..digits].parse::<usize>()
+		.map(|n| n * unit)
+		.map_err(|_| format!("Invalid size {}, expected e.g. 64MB.", size))
+}
 
+// A position, either as moves or as in `Position::to_notation`, then its
+// expected score.
+fn parse_line(line: &str) -> Result<(Position, Outcome), String> {
+	let (position, score) = line.trim().rsplit_once(' ').ok_or("missing score")?;
+	let score = score.parse::<i8>().map_err(|_| format!("invalid score {}", score))?;
+	let position = if position.contains('/') {
+		Position::from_notation(CONNECT_FOUR, position).map_err(|err| err.to_string())?
+	} else {
+		Position::try_from(position).map_err(|err| err.to_string())?
+	};
+	Ok((position, score.into()))
+}
 
-fn test_file(title: &str, filename: String, strongly: bool) -> Result<(), String> {
+// `exact` strongly solves with null-window searches, see `Solver::solve_exact_position`,
+// and more than one thread with `Solver::solve_parallel_position`. Unless
+// `reset`, positions solved by previous lines are kept.
+fn test_file(
+	solver: &mut Solver,
+	title: &str,
+	filename: String,
+	strongly: bool,
+	exact: bool,
+	threads: usize,
+	reset: bool,
+) -> Result<(), String> {
 	let start = std::time::Instant::now();
 	fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>>
 	where P: AsRef<Path>, {
@@ -42,23 +123,33 @@ fn test_file(title: &str, filename: String, strongly: bool) -> Result<(), String
 	}
 	let mut sum_durations = 0u128;
 	let mut sum_positions_checked = 0u128;
+	let mut sum_hits = 0u64;
+	let mut sum_lookups = 0u64;
+	let mut sum_collisions = 0u64;
 	let mut count = 0u16;
 
 	for line in read_lines(filename).unwrap() {
 		if start.elapsed().as_secs() > 1_200 { break }
 
 		let line_str = line.unwrap();
-		let mut split = line_str.split(' ');
-		let pos_str = split.next().unwrap().to_string();
-		let expected_outcome: Outcome = split.next().unwrap().parse::<i8>().unwrap().into();
+		let (position, expected_outcome) = match parse_line(&line_str) {
+			Ok(parsed) => parsed,
+			Err(err) => return Err(format!("{}:{}: {}\n\tinvalid position, {}", title, count, line_str.trim(), err)),
+		};
+		if reset { solver.reset() }
 		let now = std::time::Instant::now();
-		let (_best_mov, positions_checked, actual_outcome) =
-			if strongly {
-				Solver::solve_str(pos_str).unwrap()
+		let result =
+			if strongly && threads > 1 {
+				solver.solve_parallel_position(position, threads)
+			} else if strongly && exact {
+				solver.solve_exact_position(position)
+			} else if strongly {
+				solver.solve_position(position)
 			} else {
-				Solver::weakly_solve_str(pos_str).unwrap()
+				solver.weakly_solve_position(position)
 			};
 		let duration = now.elapsed().as_nanos();
+		let actual_outcome = result.outcome;
 
 		if strongly {
 			if actual_outcome != expected_outcome {
@@ -87,13 +178,16 @@ fn test_file(title: &str, filename: String, strongly: bool) -> Result<(), String
 		}
 
 		sum_durations += duration;
-		sum_positions_checked += positions_checked;
+		sum_positions_checked += result.positions_checked;
+		sum_hits += result.table_stats.hits;
+		sum_lookups += result.table_stats.hits + result.table_stats.misses;
+		sum_collisions += result.table_stats.collisions;
 		count += 1;
 	}
 
 	let mean_nanos = sum_durations as f64/ count as f64;
 
-	println!("test={} mean_time={} mean_nb_pos={:.1} strongly_solved={} completion={}",
+	println!("test={} mean_time={} mean_nb_pos={:.1} tt_hit_rate={:.3} mean_tt_collisions={:.1} strongly_solved={} completion={}",
 		title,
 		if mean_nanos > 1e9 {
 			format!("{:.4}s", mean_nanos / 1e9)
@@ -105,6 +199,8 @@ fn test_file(title: &str, filename: String, strongly: bool) -> Result<(), String
 			format!("{:.4}ns", mean_nanos)
 		},
 		sum_positions_checked as f64 / count as f64,
+		sum_hits as f64 / sum_lookups as f64,
+		sum_collisions as f64 / count as f64,
 		strongly,
 		count,
 	);
@@ -0,0 +1,338 @@
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::ops::{Add, BitAnd, BitAndAssign, BitOr, BitOrAssign, BitXor, Not, Sub};
+
+use crate::position::GridSize;
+
+/// An unsigned integer used as a bitboard. A grid of `width` columns and
+/// `height` rows needs `width * (height + 1)` bits, see `GridSize::bits`.
+/// Only implemented for `u64` and `u128`.
+pub trait Bits:
+	sealed::Sealed
+	+ Copy + Eq + Ord + Hash + Debug + Send + Sync
+	+ Add<Output = Self> + Sub<Output = Self>
+	+ BitAnd<Output = Self> + BitOr<Output = Self> + BitXor<Output = Self>
+	+ BitAndAssign + BitOrAssign
+	+ Not<Output = Self>
+{
+	const ZERO: Self;
+	const ONE: Self;
+	const BITS: u32;
+
+	// Shifts that give zero rather than overflowing when `n >= BITS`.
+	fn shl(self, n: u32) -> Self;
+	fn shr(self, n: u32) -> Self;
+
+	fn count_ones(self) -> u32;
+	fn trailing_zeros(self) -> u32;
+}
+
+// Helpers of the crate, which also keep `Bits` from being implemented
+// elsewhere.
+mod sealed {
+	pub trait Sealed {
+		// Quotient and remainder, to index hash tables.
+		fn div_rem(self, n: u64) -> (u128, u64);
+		// Lossless, to store keys whatever their size.
+		fn widen(self) -> u128;
+	}
+}
+
+macro_rules! impl_bits {
+	($($t:ty),*) => {$(
+		impl Bits for $t {
+			const ZERO: Self = 0;
+			const ONE: Self = 1;
+			const BITS: u32 = <$t>::BITS;
+
+			fn shl(self, n: u32) -> Self { self.checked_shl(n).unwrap_or(0) }
+			fn shr(self, n: u32) -> Self { self.checked_shr(n).unwrap_or(0) }
+
+			fn count_ones(self) -> u32 { <$t>::count_ones(self) }
+			fn trailing_zeros(self) -> u32 { <$t>::trailing_zeros(self) }
+		}
+
+		impl sealed::Sealed for $t {
+			fn div_rem(self, n: u64) -> (u128, u64) { ((self / n as $t) as u128, (self % n as $t) as u64) }
+			fn widen(self) -> u128 { self as u128 }
+		}
+	)*}
+}
+
+impl_bits!(u64, u128);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+	Up,
+	Down,
+	Left,
+	Right,
+	UpLeft,
+	UpRight,
+	DownLeft,
+	DownRight,
+}
+
+impl Direction {
+	pub const ALL: [Direction; 8] = [
+		Direction::Up, Direction::Down, Direction::Left, Direction::Right,
+		Direction::UpLeft, Direction::UpRight, Direction::DownLeft, Direction::DownRight,
+	];
+
+	pub fn opposite(self) -> Direction {
+		match self {
+			Direction::Up => Direction::Down,
+			Direction::Down => Direction::Up,
+			Direction::Left => Direction::Right,
+			Direction::Right => Direction::Left,
+			Direction::UpLeft => Direction::DownRight,
+			Direction::UpRight => Direction::DownLeft,
+			Direction::DownLeft => Direction::UpRight,
+			Direction::DownRight => Direction::UpLeft,
+		}
+	}
+
+	// Bits between a cell and its neighbour, which has the greater index
+	// when `forward`.
+	#[inline]
+	fn step(self, size: GridSize) -> (u32, bool) {
+		let column = size.height as u32 + 1;
+		match self {
+			Direction::Up => (1, true),
+			Direction::Down => (1, false),
+			Direction::Right => (column, true),
+			Direction::Left => (column, false),
+			Direction::UpRight => (column + 1, true),
+			Direction::DownLeft => (column + 1, false),
+			Direction::DownRight => (column - 1, true),
+			Direction::UpLeft => (column - 1, false),
+		}
+	}
+}
+
+/// A set of cells of a grid, one bit each, column by column from the bottom
+/// left cell. Each column has a sentinel bit on top of it, so that cells
+/// shifted out of a column do not wrap into the next one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Bitboard<B: Bits = u64> {
+	bits: B,
+	size: GridSize,
+}
+
+impl<B: Bits> Bitboard<B> {
+	pub fn new(bits: B, size: GridSize) -> Bitboard<B> {
+		Bitboard { bits, size }
+	}
+
+	pub fn empty(size: GridSize) -> Bitboard<B> {
+		Bitboard::new(B::ZERO, size)
+	}
+
+	// Every cell of the grid, sentinels excepted.
+	pub fn full(size: GridSize) -> Bitboard<B> {
+		let bottom = Bitboard::<B>::bottom_row(size).bits;
+		Bitboard::new(bottom.shl(size.height as u32) - bottom, size)
+	}
+
+	pub fn bottom_row(size: GridSize) -> Bitboard<B> {
+		(0..size.width).fold(Bitboard::empty(size), |row, col| row | Bitboard::cell(size, col, 0))
+	}
+
+	pub fn cell(size: GridSize, col: u8, row: u8) -> Bitboard<B> {
+		Bitboard::new(B::ONE.shl(col as u32 * (size.height as u32 + 1) + row as u32), size)
+	}
+
+	// Every cell of a column, its sentinel excepted.
+	pub fn column_mask(size: GridSize, col: u8) -> Bitboard<B> {
+		let column = B::ONE.shl(size.height as u32) - B::ONE;
+		Bitboard::new(column.shl(col as u32 * (size.height as u32 + 1)), size)
+	}
+
+	pub fn bits(&self) -> B {
+		self.bits
+	}
+
+	pub fn size(&self) -> GridSize {
+		self.size
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.bits == B::ZERO
+	}
+
+	pub fn count_ones(&self) -> u32 {
+		self.bits.count_ones()
+	}
+
+	pub fn contains(&self, col: u8, row: u8) -> bool {
+		!(*self & Bitboard::cell(self.size, col, row)).is_empty()
+	}
+
+	// The cells of this board in column `col`.
+	pub fn column(&self, col: u8) -> Bitboard<B> {
+		*self & Bitboard::column_mask(self.size, col)
+	}
+
+	// `(column, row)` of the cells set, column by column from the bottom.
+	pub fn cells(&self) -> impl Iterator<Item = (u8, u8)> {
+		let size = self.size;
+		let mut bits = (*self & Bitboard::full(size)).bits;
+		std::iter::from_fn(move || {
+			if bits == B::ZERO { return None }
+			let index = bits.trailing_zeros();
+			bits &= bits - B::ONE;
+			let column = size.height as u32 + 1;
+			Some(((index / column) as u8, (index % column) as u8))
+		})
+	}
+
+	// Moves every cell `n` steps towards `direction`. Cells crossing the
+	// top or bottom of the grid land on sentinels, the ones crossing its
+	// sides are lost.
+	#[inline]
+	pub fn shift(&self, direction: Direction, n: u32) -> Bitboard<B> {
+		let (step, forward) = direction.step(self.size);
+		let bits = if forward { self.bits.shl(n * step) } else { self.bits.shr(n * step) };
+		Bitboard::new(bits, self.size)
+	}
+
+	// Parses rows of `0` and `1` from the top, sentinel row included, as
+	// written by `Display`.
+	pub fn from_diagram(size: GridSize, diagram: &str) -> Option<Bitboard<B>> {
+		let rows: Vec<&str> = diagram.trim().lines().map(str::trim).collect();
+		if rows.len() != size.height as usize + 1 { return None }
+
+		let mut board = Bitboard::empty(size);
+		for (row, line) in rows.iter().rev().enumerate() {
+			if line.len() != size.width as usize { return None }
+			for (col, c) in line.chars().enumerate() {
+				match c {
+					'0' => (),
+					'1' => board.bits |= B::ONE.shl(col as u32 * (size.height as u32 + 1) + row as u32),
+					_ => return None,
+				}
+			}
+		}
+		Some(board)
+	}
+}
+
+impl<B: Bits> BitAnd for Bitboard<B> {
+	type Output = Bitboard<B>;
+	fn bitand(self, other: Bitboard<B>) -> Bitboard<B> { Bitboard::new(self.bits & other.bits, self.size) }
+}
+
+impl<B: Bits> BitOr for Bitboard<B> {
+	type Output = Bitboard<B>;
+	fn bitor(self, other: Bitboard<B>) -> Bitboard<B> { Bitboard::new(self.bits | other.bits, self.size) }
+}
+
+impl<B: Bits> BitXor for Bitboard<B> {
+	type Output = Bitboard<B>;
+	fn bitxor(self, other: Bitboard<B>) -> Bitboard<B> { Bitboard::new(self.bits ^ other.bits, self.size) }
+}
+
+impl<B: Bits> Not for Bitboard<B> {
+	type Output = Bitboard<B>;
+	fn not(self) -> Bitboard<B> { Bitboard::new(!self.bits, self.size) }
+}
+
+// Draws the grid column by column, sentinel row included.
+impl<B: Bits> std::fmt::Display for Bitboard<B> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let column = self.size.height as u32 + 1;
+		for row in (0..column).rev() {
+			writeln!(f, "{}", (0..self.size.width as u32).map(
+				|col| if self.bits.shr(row + col * column) & B::ONE == B::ZERO { '0' } else { '1' }
+			).collect::<String>())?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::position::GRID_SIZE;
+
+	#[test]
+	fn from_diagram() {
+		assert_eq!(
+			Bitboard::new((1u64 | 2 | 4) << (GRID_SIZE.height + 1), GRID_SIZE),
+			Bitboard::from_diagram(GRID_SIZE, "
+				0000000
+				0000000
+				0000000
+				0000000
+				0100000
+				0100000
+				0100000
+			").unwrap()
+		);
+		assert_eq!(Bitboard::<u64>::from_diagram(GRID_SIZE, "0100000"), None);
+		assert_eq!(
+			Bitboard::<u64>::from_diagram(GRID_SIZE, "
+				0000000
+				1100001
+				0100000
+				0011001
+				1110111
+				1011000
+				1100100
+			").unwrap().to_string(),
+			"\
+				0000000\n\
+				1100001\n\
+				0100000\n\
+				0011001\n\
+				1110111\n\
+				1011000\n\
+				1100100\n\
+			"
+		)
+	}
+
+	#[test]
+	fn masks() {
+		let size = GridSize::new(3, 2);
+		assert_eq!(Bitboard::<u64>::full(size).bits(), 0b011_011_011);
+		assert_eq!(Bitboard::<u64>::bottom_row(size).bits(), 0b001_001_001);
+		assert_eq!(Bitboard::<u64>::column_mask(size, 1).bits(), 0b000_011_000);
+		assert_eq!(Bitboard::<u64>::cell(size, 2, 1).bits(), 0b010_000_000);
+		assert_eq!(Bitboard::<u128>::full(GRID_SIZE).count_ones(), 42);
+	}
+
+	#[test]
+	fn cells_and_columns() {
+		let board = Bitboard::<u64>::from_diagram(GRID_SIZE, "
+			0000000
+			0000000
+			0000000
+			0010000
+			0010000
+			0000000
+			1000001
+		").unwrap();
+		assert_eq!(board.count_ones(), 4);
+		assert_eq!(board.cells().collect::<Vec<_>>(), [(0, 0), (2, 2), (2, 3), (6, 0)]);
+		assert_eq!(board.column(2).cells().collect::<Vec<_>>(), [(2, 2), (2, 3)]);
+		assert!(board.column(1).is_empty());
+		assert!(board.contains(6, 0) && !board.contains(6, 1));
+		// Sentinels and bits out of the grid are not cells.
+		assert_eq!((!Bitboard::<u64>::empty(GRID_SIZE)).cells().count(), 42);
+	}
+
+	#[test]
+	fn shift() {
+		let center = Bitboard::<u64>::cell(GRID_SIZE, 3, 2);
+		let expected = [(3, 3), (3, 1), (2, 2), (4, 2), (2, 3), (4, 3), (2, 1), (4, 1)];
+		for (direction, (col, row)) in Direction::ALL.into_iter().zip(expected) {
+			assert_eq!(center.shift(direction, 1), Bitboard::cell(GRID_SIZE, col, row), "{:?}", direction);
+			assert_eq!(center.shift(direction, 1).shift(direction.opposite(), 1), center);
+		}
+		assert_eq!(center.shift(Direction::UpRight, 2), Bitboard::cell(GRID_SIZE, 5, 4));
+		// Out of the grid.
+		assert!(center.shift(Direction::Left, 4).is_empty());
+		assert_eq!(center.shift(Direction::Up, 4), Bitboard::cell(GRID_SIZE, 3, 6));
+	}
+}
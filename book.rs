@@ -0,0 +1,196 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::bitboard::Bits;
+use crate::position::{GridSize, Position, Rules};
+
+const MAGIC: &[u8; 4] = b"C4BK";
+const VERSION: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct BookEntry {
+	key: u128,
+	// Played in the position with the canonical key.
+	best_move: u8,
+	score: i8,
+}
+
+/// Exact scores of every position up to a given number of moves, see
+/// `Solver::build_book`.
+///
+/// The file starts with a header (magic, version, rules, depth, number of
+/// entries) followed by the entries sorted by key. Each one is the key on
+/// as few bytes as the grid allows, then the best move and the score.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Book {
+	rules: Rules,
+	depth: u8,
+	entries: Vec<BookEntry>,
+}
+
+impl Book {
+	pub fn new(rules: Rules, depth: u8) -> Book {
+		Book { rules, depth, entries: Vec::new() }
+	}
+
+	pub fn rules(&self) -> Rules {
+		self.rules
+	}
+
+	// Positions with more moves are never in the book.
+	pub fn depth(&self) -> u8 {
+		self.depth
+	}
+
+	pub fn len(&self) -> usize {
+		self.entries.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.entries.is_empty()
+	}
+
+	// Cached `(best_move, score)` of a position.
+	pub fn get<B: Bits>(&self, position: &Position<B>) -> Option<(u8, i8)> {
+		if position.move_count > self.depth || position.rules() != self.rules { return None }
+
+		let key = position.canonical_key();
+		let index = self.entries.binary_search_by_key(&key.widen(), |entry| entry.key).ok()?;
+		let entry = self.entries[index];
+		let best_move = if key != position.key() { position.mirror_column(entry.best_move) } else { entry.best_move };
+		Some((best_move, entry.score))
+	}
+
+	// Adds `(position, best_move, score)` entries, replacing known positions.
+	pub fn extend<B: Bits>(&mut self, scores: impl IntoIterator<Item = (Position<B>, u8, i8)>) {
+		for (position, best_move, score) in scores {
+			let key = position.canonical_key();
+			let best_move = if key != position.key() { position.mirror_column(best_move) } else { best_move };
+			self.entries.push(BookEntry { key: key.widen(), best_move, score });
+		}
+		// Stable, so the last entry of a key is the one kept.
+		self.entries.sort_by_key(|entry| entry.key);
+		self.entries.reverse();
+		self.entries.dedup_by_key(|entry| entry.key);
+		self.entries.reverse();
+	}
+
+	pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+		let mut file = BufWriter::new(File::create(path)?);
+		file.write_all(MAGIC)?;
+		file.write_all(&[
+			VERSION,
+			self.rules.size.width,
+			self.rules.size.height,
+			self.rules.alignment,
+			self.depth,
+		])?;
+		file.write_all(&(self.entries.len() as u64).to_le_bytes())?;
+
+		let key_bytes = Book::key_bytes(self.rules.size);
+		for entry in &self.entries {
+			file.write_all(&entry.key.to_le_bytes()[..key_bytes])?;
+			file.write_all(&[entry.best_move, entry.score as u8])?;
+		}
+		file.flush()
+	}
+
+	pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Book> {
+		let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+		let mut file = BufReader::new(File::open(path)?);
+
+		let mut header = [0u8; 17];
+		file.read_exact(&mut header[..5])?;
+		if &header[..4] != MAGIC { return Err(invalid("not an opening book")) }
+		if header[4] != VERSION { return Err(invalid("unsupported opening book version")) }
+		file.read_exact(&mut header[5..])?;
+
+		let size = GridSize::new(header[5], header[6]);
+		let alignment = header[7];
+		if Rules::new(size, alignment).check::<u128>().is_err() {
+			return Err(invalid("invalid opening book rules"))
+		}
+		let depth = header[8];
+		let count = u64::from_le_bytes(header[9..].try_into().unwrap());
+
+		let key_bytes = Book::key_bytes(size);
+		let mut entries = Vec::new();
+		let mut buffer = [0u8; 18];
+		for _ in 0..count {
+			file.read_exact(&mut buffer[..key_bytes + 2])?;
+			let mut key = [0u8; 16];
+			key[..key_bytes].copy_from_slice(&buffer[..key_bytes]);
+			entries.push(BookEntry {
+				key: u128::from_le_bytes(key),
+				best_move: buffer[key_bytes],
+				score: buffer[key_bytes + 1] as i8,
+			});
+		}
+		if entries.windows(2).any(|pair| pair[0].key >= pair[1].key) {
+			return Err(invalid("opening book entries are not sorted"))
+		}
+		// A win takes at most the last cell, a loss the one before.
+		let max_score = (size.width as i32 * size.height as i32 + 1) / 2;
+		if entries.iter().any(|entry| entry.best_move >= size.width || (entry.score as i32).abs() > max_score) {
+			return Err(invalid("invalid opening book entry"))
+		}
+
+		Ok(Book { rules: Rules::new(size, alignment), depth, entries })
+	}
+
+	// Keys are below `2^bits`, see `Position::key`.
+	fn key_bytes(size: GridSize) -> usize {
+		size.bits().div_ceil(8) as usize
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::position::CONNECT_FOUR;
+
+	#[test]
+	fn get_and_extend() {
+		let mut book = Book::new(CONNECT_FOUR, 4);
+		let pos = Position::try_from("12").unwrap();
+		assert_eq!(book.get(&pos), None);
+
+		book.extend([(pos, 2, -1)]);
+		assert_eq!(book.get(&pos), Some((2, -1)));
+		// The mirror position shares the entry.
+		assert_eq!(book.get(&pos.mirror()), Some((4, -1)));
+
+		book.extend([(pos.mirror(), 3, 0), (Position::new_empty(), 3, 1)]);
+		assert_eq!(book.len(), 2);
+		assert_eq!(book.get(&pos), Some((3, 0)));
+		assert_eq!(book.get(&Position::new_empty()), Some((3, 1)));
+
+		// Too deep or other rules.
+		assert_eq!(book.get(&Position::try_from("12345").unwrap()), None);
+		assert_eq!(book.get(&Position::<u128>::with_rules(CONNECT_FOUR)), Some((3, 1)));
+		assert_eq!(book.get(&Position::<u64>::with_rules(Rules::new(CONNECT_FOUR.size, 3))), None);
+	}
+
+	#[test]
+	fn save_and_load() {
+		let path = std::env::temp_dir().join(format!("connect-four-book-{}", std::process::id()));
+		let mut book = Book::new(CONNECT_FOUR, 3);
+		book.extend(["", "4", "44", "13"].map(|moves| (Position::try_from(moves).unwrap(), 3, -2)));
+		book.save(&path).unwrap();
+		assert_eq!(std::fs::metadata(&path).unwrap().len(), 17 + 4 * 9);
+		assert_eq!(Book::load(&path).unwrap(), book);
+
+		// Moves out of the grid and impossible scores.
+		for (best_move, score) in [(7, 0), (200, 0), (3, 22), (3, -22)] {
+			let mut bad = Book::new(CONNECT_FOUR, 3);
+			bad.extend([(Position::try_from("1").unwrap(), best_move, score)]);
+			bad.save(&path).unwrap();
+			assert_eq!(Book::load(&path).unwrap_err().kind(), io::ErrorKind::InvalidData, "{} {}", best_move, score);
+		}
+
+		std::fs::write(&path, b"C4BK\x02").unwrap();
+		assert_eq!(Book::load(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+		std::fs::remove_file(&path).unwrap();
+	}
+}
@@ -0,0 +1,29 @@
+use connect_four::{Solver, CONNECT_FOUR};
+
+const USAGE: &str = "Usage: build_book DEPTH FILE";
+
+fn main() {
+	let args: Vec<String> = std::env::args().skip(1).collect();
+	let (depth, path) = match args.as_slice() {
+		[depth, path] => match depth.parse::<u8>() {
+			Ok(depth) if depth < CONNECT_FOUR.size.width * CONNECT_FOUR.size.height => (depth, path),
+			_ => {
+				eprintln!("Invalid depth {}.\n{}", depth, USAGE);
+				std::process::exit(2)
+			},
+		},
+		_ => {
+			eprintln!("{}", USAGE);
+			std::process::exit(2)
+		},
+	};
+
+	let start = std::time::Instant::now();
+	let book = Solver::new().build_book::<u64>(CONNECT_FOUR, depth);
+	println!("Solved {} positions up to {} moves in {:.1?}.", book.len(), depth, start.elapsed());
+
+	if let Err(err) = book.save(path) {
+		eprintln!("Could not write {}: {}", path, err);
+		std::process::exit(1)
+	}
+}
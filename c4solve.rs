@@ -0,0 +1,116 @@
+use std::io::{self, BufRead, Write};
+use std::time::Instant;
+
+use connect_four::{Position, Solver, Status, CONNECT_FOUR};
+
+const USAGE: &str = "Usage: c4solve [--weak] [--analyze] < positions
+
+Reads one position per line, as the columns played from 1 or as in
+`Position::to_notation`, optionally followed by its expected score.
+Prints the position, its score, the best move, the number of positions
+checked and the time taken in microseconds, the best move being - once
+the game is over. With --analyze, prints the
+score of each column instead, -1000 for those that cannot be played,
+the expected score being the best one.
+With --weak, scores are only -1, 0 or 1: loss, draw or win.";
+
+// Column scores are printed this way when not playable, as by gamesolver.
+const INVALID_MOVE: i32 = -1000;
+
+fn main() {
+	let mut weak = false;
+	let mut analyze = false;
+	for arg in std::env::args().skip(1) {
+		match arg.as_str() {
+			"--weak" | "-w" => weak = true,
+			"--analyze" | "-a" => analyze = true,
+			"--help" | "-h" => return println!("{}", USAGE),
+			_ => {
+				eprintln!("Unknown argument {}.\n{}", arg, USAGE);
+				std::process::exit(2)
+			},
+		}
+	}
+
+	let mut solver = Solver::new();
+	let mut failed = false;
+	let mut out = io::stdout().lock();
+	for (number, line) in io::stdin().lock().lines().enumerate() {
+		let line = match line {
+			Ok(line) => line,
+			Err(err) => {
+				eprintln!("Could not read line {}: {}", number + 1, err);
+				std::process::exit(1)
+			},
+		};
+		if line.trim().is_empty() { continue }
+
+		let (input, position, expected) = match parse_line(&line) {
+			Ok(parsed) => parsed,
+			Err(err) => {
+				eprintln!("line {}: invalid position {}, {}", number + 1, line.trim(), err);
+				failed = true;
+				continue
+			},
+		};
+		solver.reset();
+
+		let (mut output, score) = if analyze {
+			let scores = solver.analyze_position(position);
+			// Once the game is over no column has a score.
+			let best = scores.iter().flatten().max().copied()
+				.unwrap_or_else(|| i8::from(&solver.solve_position(position).outcome));
+			let scores = scores.into_iter()
+				.map(|score| match score {
+					Some(score) if weak => score.signum().to_string(),
+					Some(score) => score.to_string(),
+					None => INVALID_MOVE.to_string(),
+				})
+				.collect::<Vec<_>>();
+			(format!("{} {}", input, scores.join(" ")), if weak { best.signum() } else { best })
+		} else {
+			let start = Instant::now();
+			let result = if weak { solver.weakly_solve_position(position) } else { solver.solve_position(position) };
+			let elapsed = start.elapsed();
+			let score = i8::from(&result.outcome);
+			let score = if weak { score.signum() } else { score };
+			// No move is left to play.
+			let best_move = if position.status() == Status::Ongoing { position.size().column_name(result.best_move) } else { '-' };
+
+			let output = format!(
+				"{} {} {} {} {}",
+				input,
+				score,
+				best_move,
+				result.positions_checked,
+				elapsed.as_micros(),
+			);
+			(output, score)
+		};
+		if let Some(expected) = expected.map(|expected| if weak { expected.signum() } else { expected }) {
+			if expected != score {
+				output.push_str(&format!(" expected {}", expected));
+				failed = true;
+			}
+		}
+		// Stops quietly once the reader is gone, e.g. piped into `head`.
+		if writeln!(out, "{}", output).is_err() { break }
+	}
+
+	if failed { std::process::exit(1) }
+}
+
+// The position as written, the position and the expected score if any.
+fn parse_line(line: &str) -> Result<(&str, Position, Option<i8>), String> {
+	let line = line.trim();
+	let (input, expected) = match line.rsplit_once(char::is_whitespace) {
+		Some((input, score)) if score.parse::<i8>().is_ok() => (input.trim(), score.parse::<i8>().ok()),
+		_ => (line, None),
+	};
+	let position = if input.contains('/') {
+		Position::from_notation(CONNECT_FOUR, input).map_err(|err| err.to_string())?
+	} else {
+		Position::try_from(input).map_err(|err| err.to_string())?
+	};
+	Ok((input, position, expected))
+}
@@ -0,0 +1,193 @@
+use crate::bitboard::Bits;
+use crate::position::{Position, PositionParseError, Status};
+
+/// A position and the moves that led to it, which can be undone and redone.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Game<B: Bits = u64> {
+	// The starting position first, then the one after each move.
+	positions: Vec<Position<B>>,
+	moves: Vec<u8>,
+	// Undone moves, the last one is redone first.
+	undone: Vec<u8>,
+}
+
+impl<B: Bits> Game<B> {
+	pub fn new(start: Position<B>) -> Game<B> {
+		Game { positions: vec![start], moves: Vec::new(), undone: Vec::new() }
+	}
+
+	pub fn position(&self) -> Position<B> {
+		*self.positions.last().unwrap()
+	}
+
+	pub fn start(&self) -> Position<B> {
+		self.positions[0]
+	}
+
+	// Columns played since the start, 0-indexed.
+	pub fn history(&self) -> &[u8] {
+		&self.moves
+	}
+
+	// Columns played since the start, see `GridSize::column_name`.
+	pub fn moves(&self) -> String {
+		self.moves.iter().map(|mov| self.start().size().column_name(*mov)).collect()
+	}
+
+	// Forgets the undone moves.
+	pub fn play(&mut self, column: u8) -> Result<(), MoveError> {
+		self.push(column)?;
+		self.undone.clear();
+		Ok(())
+	}
+
+	// Returns the column of the move taken back, if any.
+	pub fn undo(&mut self) -> Option<u8> {
+		let mov = self.moves.pop()?;
+		self.positions.pop();
+		self.undone.push(mov);
+		Some(mov)
+	}
+
+	// Returns the column of the move played again, if any.
+	pub fn redo(&mut self) -> Option<u8> {
+		let mov = self.undone.pop()?;
+		self.push(mov).unwrap();
+		Some(mov)
+	}
+
+	pub fn can_undo(&self) -> bool {
+		!self.moves.is_empty()
+	}
+
+	pub fn can_redo(&self) -> bool {
+		!self.undone.is_empty()
+	}
+
+	fn push(&mut self, column: u8) -> Result<(), MoveError> {
+		let position = self.position();
+		if position.status() != Status::Ongoing { return Err(MoveError::GameOver) }
+		if column >= position.size().width {
+			return Err(MoveError::ColumnOutOfRange { column: column as u32 + 1 })
+		}
+		if !position.can_play(column) { return Err(MoveError::ColumnFull { column: column + 1 }) }
+		self.positions.push(position.next(column));
+		self.moves.push(column);
+		Ok(())
+	}
+}
+
+/// Why a move cannot be played, columns are counted from 1 as in
+/// `PositionParseError`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MoveError {
+	ColumnOutOfRange { column: u32 },
+	ColumnFull { column: u8 },
+	// Someone already won or the grid is full.
+	GameOver,
+}
+
+impl std::fmt::Display for MoveError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			MoveError::ColumnOutOfRange { column } => write!(f, "column {} out of the grid", column),
+			MoveError::ColumnFull { column } => write!(f, "column {} already full", column),
+			MoveError::GameOver => write!(f, "the game is over"),
+		}
+	}
+}
+
+impl std::error::Error for MoveError {}
+
+impl Default for Game {
+	fn default() -> Game {
+		Game::new(Position::new_empty())
+	}
+}
+
+impl<B: Bits> From<Position<B>> for Game<B> {
+	fn from(start: Position<B>) -> Game<B> {
+		Game::new(start)
+	}
+}
+
+impl TryFrom<&str> for Game {
+	type Error = PositionParseError;
+	fn try_from(s: &str) -> Result<Self, Self::Error> {
+		// Same errors as for a position.
+		Position::try_from(s)?;
+		let mut game = Game::default();
+		let size = game.start().size();
+		for mov in s.chars().map(|c| size.column_number(c).unwrap() as u8 - 1) {
+			game.play(mov).unwrap();
+		}
+		Ok(game)
+	}
+}
+
+impl TryFrom<String> for Game {
+	type Error = PositionParseError;
+	fn try_from(s: String) -> Result<Self, Self::Error> {
+		Game::try_from(s.as_str())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn play_undo_redo() {
+		let mut game = Game::default();
+		assert_eq!(game.undo(), None);
+		game.play(3).unwrap();
+		game.play(2).unwrap();
+		assert_eq!(game.moves(), "43");
+		assert_eq!(game.position(), Position::try_from("43").unwrap());
+
+		assert_eq!(game.undo(), Some(2));
+		assert_eq!(game.undo(), Some(3));
+		assert_eq!(game.position(), Position::new_empty());
+		assert!(!game.can_undo() && game.can_redo());
+
+		assert_eq!(game.redo(), Some(3));
+		assert_eq!(game.moves(), "4");
+		// Playing forgets what was undone.
+		game.play(0).unwrap();
+		assert_eq!(game.redo(), None);
+		assert_eq!(game.history(), [3, 0]);
+	}
+
+	#[test]
+	fn invalid_moves() {
+		let mut game = Game::try_from("111111").unwrap();
+		assert_eq!(game.play(0), Err(MoveError::ColumnFull { column: 1 }));
+		assert_eq!(game.play(7), Err(MoveError::ColumnOutOfRange { column: 8 }));
+		assert_eq!(game.play(255).unwrap_err().to_string(), "column 256 out of the grid");
+		assert_eq!(game.moves(), "111111");
+		assert_eq!(Game::try_from("1111111"), Err(PositionParseError::ColumnFull { index: 6, column: 1 }));
+		assert_eq!(Game::try_from("X"), Err(PositionParseError::InvalidCharacter { index: 0, character: 'X' }));
+	}
+
+	#[test]
+	fn game_over() {
+		let mut game = Game::try_from("121212").unwrap();
+		game.play(0).unwrap();
+		assert_eq!(game.play(2), Err(MoveError::GameOver));
+		assert!(matches!(game.position().status(), Status::Won(_)));
+		game.undo();
+		assert_eq!(game.position().status(), Status::Ongoing);
+		game.redo();
+		assert!(matches!(game.position().status(), Status::Won(_)));
+	}
+
+	#[test]
+	fn from_position() {
+		let start = Position::try_from("44").unwrap();
+		let mut game = Game::from(start);
+		game.play(4).unwrap();
+		assert_eq!(game.moves(), "5");
+		assert_eq!(game.start(), start);
+		assert_eq!(game.position(), Position::try_from("445").unwrap());
+	}
+}
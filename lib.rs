@@ -0,0 +1,38 @@
+//! A connect four solver, for any grid size and number of pieces to align.
+//!
+//! ```
+//! use connect_four::{Outcome, Position, Solver};
+//!
+//! let position = Position::try_from("4444233333246").unwrap();
+//! let result = Solver::solve(position);
+//! assert!(matches!(result.outcome, Outcome::Win(_)));
+//! ```
+//!
+//! # Stability
+//!
+//! The items exported here are the public API: positions and their
+//! notations, games, solvers and their results. They only change in
+//! incompatible ways with a new minor version while below 1.0. Modules are
+//! private, and so is everything not exported, such as the transposition
+//! table. The book and table files carry a version number, older files are
+//! rejected rather than misread.
+//!
+//! The game window is behind the `gui` feature, enabled by default. Use
+//! `default-features = false` to depend on the solver alone.
+
+mod bitboard;
+mod book;
+mod game;
+mod position;
+mod solver;
+mod transposition_table;
+
+pub use crate::bitboard::{Bitboard, Bits, Direction};
+pub use crate::book::Book;
+pub use crate::game::{Game, MoveError};
+pub use crate::position::{
+	DiagramParseError, GridSize, NotationParseError, Player, Position, PositionParseError, Rules, RulesError,
+	Status, CONNECT_FOUR, GRID_SIZE, MAX_ALIGNMENT,
+};
+pub use crate::solver::{Budget, Outcome, Solver, SolverResult};
+pub use crate::transposition_table::{TableStats, DEFAULT_TABLE_SIZE};
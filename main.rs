@@ -1,12 +1,11 @@
-use std::collections::LinkedList;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, TryRecvError};
+use std::sync::Arc;
+use std::thread;
 use ggez::event::{KeyCode, KeyMods};
 use ggez::{event, graphics, Context, GameResult};
 
-mod position;
-mod solver;
-
-use crate::position::{Position, GRID_SIZE};
-use crate::solver::Solver;
+use connect_four::{Budget, Game, Solver, SolverResult, Status, GRID_SIZE};
 
 const GRID_CELL_SIZE_PX: usize = 256;
 
@@ -29,7 +28,7 @@ impl GridPosition {
     fn point(&self) -> [f32; 2] {
         let x = GRID_CELL_SIZE_PX as f32 / 2.0 + (self.x as usize * GRID_CELL_SIZE_PX) as f32;
         let y = GRID_CELL_SIZE_PX as f32 / 2.0 + ((GRID_SIZE.width - 2 - self.y) as usize * GRID_CELL_SIZE_PX) as f32;
-        [x as f32, y as f32]
+        [x, y]
     }
 
     fn radius(&self) -> f32 {
@@ -47,7 +46,9 @@ enum Move {
     Left,
     Right,
     Drop,
-    IA
+    IA,
+    Undo,
+    Redo,
 }
 
 impl Move {
@@ -60,6 +61,9 @@ impl Move {
             KeyCode::Return => Some(Move::Drop),
             KeyCode::Space => Some(Move::Drop),
             KeyCode::I => Some(Move::IA),
+            KeyCode::Back => Some(Move::Undo),
+            KeyCode::U => Some(Move::Undo),
+            KeyCode::R => Some(Move::Redo),
             _ => None,
         }
     }
@@ -121,41 +125,45 @@ impl Who {
 #[derive(Clone, Copy, Debug)]
 enum Outcome { Draw, Win }
 
+// A search running on a worker thread. The solver is sent back with the
+// result, so its transposition table is kept from one move to the next.
+struct Thinking {
+    cancel: Arc<AtomicBool>,
+    receiver: mpsc::Receiver<(Solver, SolverResult)>,
+}
+
 struct GameState {
-    position: Position,
+    game: Game,
     cursor: u8,
     who: Who,
-    moves: LinkedList<u8>,
-    finished: Option<Outcome>
+    finished: Option<Outcome>,
+    // Taken by the worker thread while thinking.
+    solver: Option<Solver>,
+    thinking: Option<Thinking>,
 }
 
 impl GameState {
     pub fn new(_ctx: &mut Context, start_position: Option<String>) -> GameState {
         println!("Starting position: {:?}", start_position);
         let position_str = start_position.unwrap_or("".to_string());
-        let (position, moves) = match Position::try_from(position_str.clone()) {
-            Ok(position) =>
-                (
-                    position,
-                    position_str
-                        .chars()
-                        .map(|c|(c.to_digit(10).unwrap() - 1) as u8)
-                        .collect::<LinkedList<u8>>()
-                ),
-            Err(_) => (Position::new_empty(), LinkedList::new())
-        };
-        GameState {
-            position: position,
+        let mut state = GameState {
+            game: Game::try_from(position_str).unwrap_or_else(|err| {
+                println!("Invalid starting position, {}.", err);
+                Game::default()
+            }),
             cursor: 3,
             who: Who::PlayerRed,
-            moves: moves,
-            finished: None
-        }
+            finished: None,
+            solver: Some(Solver::new()),
+            thinking: None,
+        };
+        state.update_finished();
+        state
     }
 
     pub fn reset(&mut self) {
-        self.moves.clear();
-        self.position = Position::new_empty();
+        self.cancel_ia_move();
+        self.game = Game::default();
         self.finished = None;
     }
 
@@ -188,34 +196,127 @@ impl GameState {
         Ok(())
     }
 
-    fn ia_move(&self) -> u8 {
-        println!("IA Playing move ({}).", self.moves.iter().fold(String::new(), |a, b| a + &(b+1).to_string()));
-        let (best_mov, _, outcome) = Solver::solve(self.position);
-        println!("outcome: {}", Solver::explain_outcome(self.position, outcome));
-        best_mov
+    fn draw_thinking(&self, ctx: &mut Context) -> GameResult<()> {
+        let veil =
+            graphics::Mesh::new_rectangle(
+                ctx,
+                graphics::DrawMode::fill(),
+                graphics::Rect::new(0.0, 0.0, SCREEN_SIZE.0, SCREEN_SIZE.1),
+                graphics::Color::from_rgba(0, 0, 0, 96)
+            )?;
+        graphics::draw(ctx, &veil, (ggez::mint::Point2 { x: 0.0, y: 0.0 },))?;
+
+        let text = graphics::Text::new(
+            graphics::TextFragment::new("thinking…").scale(graphics::PxScale::from(96.0))
+        );
+        let dimensions = text.dimensions(ctx);
+        let point = ggez::mint::Point2 {
+            x: (SCREEN_SIZE.0 - dimensions.w) / 2.0,
+            y: (SCREEN_SIZE.1 - dimensions.h) / 2.0,
+        };
+        graphics::draw(ctx, &text, (point, graphics::Color::from(Palette::White)))
+    }
+
+    fn is_thinking(&self) -> bool {
+        matches!(&self.thinking, Some(thinking) if !thinking.cancel.load(Ordering::Relaxed))
+    }
+
+    // The move is played by `update` once the worker is done.
+    fn start_ia_move(&mut self) {
+        if self.finished.is_some() {
+            self.reset();
+            return
+        }
+        // A cancelled search has not given the solver back yet.
+        let mut solver = match self.solver.take() {
+            Some(solver) => solver,
+            None => return,
+        };
+
+        println!("IA Playing move ({}).", self.game.moves());
+        let cancel = Arc::new(AtomicBool::new(false));
+        solver.set_budget(Budget { cancel: Some(cancel.clone()), ..Budget::default() });
+
+        let (sender, receiver) = mpsc::channel();
+        let position = self.game.position();
+        thread::spawn(move || {
+            let result = solver.solve_position(position);
+            // Fails only if the game was closed meanwhile.
+            let _ = sender.send((solver, result));
+        });
+        self.thinking = Some(Thinking { cancel, receiver });
+    }
+
+    fn cancel_ia_move(&mut self) {
+        if self.is_thinking() {
+            self.thinking.as_ref().unwrap().cancel.store(true, Ordering::Relaxed);
+            println!("IA move cancelled.");
+        }
+    }
+
+    fn receive_ia_move(&mut self) {
+        let received = match &self.thinking {
+            Some(thinking) => thinking.receiver.try_recv(),
+            None => return,
+        };
+        match received {
+            Ok((solver, result)) => {
+                let cancelled = !self.is_thinking();
+                self.solver = Some(solver);
+                self.thinking = None;
+                if cancelled { return }
+
+                println!("outcome: {}", Solver::explain_outcome(self.game.position(), &result));
+                self.try_drop(result.best_move);
+            },
+            Err(TryRecvError::Empty) => (),
+            // The worker panicked, and its solver is lost.
+            Err(TryRecvError::Disconnected) => {
+                self.solver = Some(Solver::new());
+                self.thinking = None;
+            },
+        }
     }
 
     fn try_drop(&mut self, column: u8) {
-        if let Some(_) = self.finished {
+        if self.finished.is_some() {
             self.reset();
             return
         }
-        if !self.position.can_play(column) { return; }
-        if self.position.wins(column) {
-            self.finished = Some(Outcome::Win);
-            println!("Game Over, win.");
+        if self.game.play(column).is_err() { return; }
+        self.update_finished();
+    }
+
+    fn undo(&mut self) {
+        if self.game.undo().is_some() {
+            self.update_finished();
         }
-        self.moves.push_back(column);
-        self.position = self.position.next(column);
-        if self.position.is_terminal() {
-            self.finished = Some(Outcome::Draw);
-            println!("Game Over, draw");
+    }
+
+    fn redo(&mut self) {
+        if self.game.redo().is_some() {
+            self.update_finished();
         }
     }
+
+    fn update_finished(&mut self) {
+        self.finished = match self.game.position().status() {
+            Status::Won(_) => {
+                println!("Game Over, win.");
+                Some(Outcome::Win)
+            },
+            Status::Draw => {
+                println!("Game Over, draw");
+                Some(Outcome::Draw)
+            },
+            Status::Ongoing => None,
+        };
+    }
 }
 
 impl event::EventHandler for GameState {
     fn update(&mut self, _ctx: &mut Context) -> GameResult<()> {
+        self.receive_ia_move();
         Ok(())
     }
 
@@ -226,20 +327,24 @@ impl event::EventHandler for GameState {
         // TODO: show the win somehow.
 
         let mut who = self.who;
-        for column in self.moves.clone() {
+        for column in self.game.history().to_vec() {
             self.draw_cell(ctx, (column, counters[column as usize]).into(), who.color())?;
             counters[column as usize] += 1;
             who = who.next();
         }
 
         for (column, count) in counters.into_iter().enumerate() {
-            for row in count..(GRID_SIZE.height as u8) {
+            for row in count..GRID_SIZE.height {
                 self.draw_cell(ctx, (column as u8, row).into(), Palette::White.into())?;
             }
         }
 
         self.draw_cursor(ctx, who.color())?;
 
+        if self.is_thinking() {
+            self.draw_thinking(ctx)?;
+        }
+
         graphics::present(ctx)
     }
 
@@ -250,11 +355,19 @@ impl event::EventHandler for GameState {
         _keymod: KeyMods,
         _repeat: bool,
     ) {
+        // Any key stops the IA, without playing.
+        if self.is_thinking() {
+            self.cancel_ia_move();
+            return
+        }
+
         match Move::from_keycode(keycode) {
             Some(Move::Left) => self.cursor = (GRID_SIZE.width + self.cursor - 1) % GRID_SIZE.width,
             Some(Move::Right) => self.cursor = (GRID_SIZE.width + self.cursor + 1) % GRID_SIZE.width ,
             Some(Move::Drop) => self.try_drop(self.cursor),
-            Some(Move::IA) => self.try_drop(self.ia_move()),
+            Some(Move::IA) => self.start_ia_move(),
+            Some(Move::Undo) => self.undo(),
+            Some(Move::Redo) => self.redo(),
             None => (),
         }
     }
@@ -1,152 +1,538 @@
+use crate::bitboard::{Bitboard, Bits, Direction};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
 pub struct GridSize { pub height: u8, pub width: u8 }
 
 pub const GRID_SIZE: GridSize = GridSize { height: 6, width: 7 };
 
-const fn bottom_row_mask(width: u64, height: u64) -> u64 {
-	match width {
-		0 => 0,
-		_ => bottom_row_mask(width - 1, height) | (1u64 << (width-1)*(height+1))
+impl GridSize {
+	pub const fn new(width: u8, height: u8) -> GridSize {
+		GridSize { height, width }
+	}
+
+	// Each column has an extra sentinel bit on top of it.
+	pub const fn bits(&self) -> u32 {
+		self.width as u32 * (self.height as u32 + 1)
+	}
+
+	pub fn fits<B: Bits>(&self) -> bool {
+		self.width > 0 && self.height > 0 && self.bits() <= B::BITS
+	}
+
+	// How a column is written in move sequences: digits from `1` while
+	// they are enough, letters from `a` on wider grids. Columns past `z`
+	// have no name and are written `?`.
+	pub fn column_name(&self, col: u8) -> char {
+		match (self.width, col) {
+			(0..=9, _) => (b'1' + col) as char,
+			(_, 0..=25) => (b'a' + col) as char,
+			_ => '?',
+		}
+	}
+
+	// Column written `c`, from 1, whether it fits in the grid or not. As
+	// for `column_name`, digits are only read up to 9 columns and letters
+	// on wider grids.
+	pub fn column_number(&self, c: char) -> Option<u32> {
+		match (self.width, c) {
+			(0..=9, '0'..='9') => c.to_digit(10),
+			(10.., 'a'..='z') => Some(c as u32 - 'a' as u32 + 1),
+			_ => None,
+		}
+	}
+}
+
+/// The variant being played: grid dimensions and how many aligned pieces
+/// are needed to win.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub struct Rules { pub size: GridSize, pub alignment: u8 }
+
+pub const MAX_ALIGNMENT: u8 = 16;
+
+pub const CONNECT_FOUR: Rules = Rules { size: GRID_SIZE, alignment: 4 };
+
+impl Rules {
+	pub const fn new(size: GridSize, alignment: u8) -> Rules {
+		Rules { size, alignment }
+	}
+
+	// Whether positions of these rules can be stored in `B`.
+	pub fn check<B: Bits>(&self) -> Result<(), RulesError> {
+		if !self.size.fits::<B>() {
+			return Err(RulesError::GridSize { width: self.size.width, height: self.size.height, available: B::BITS })
+		}
+		if self.alignment == 0 || self.alignment > MAX_ALIGNMENT {
+			return Err(RulesError::Alignment { alignment: self.alignment })
+		}
+		Ok(())
 	}
 }
 
-const BOTTOM_ROW_MASK: u64 = bottom_row_mask(GRID_SIZE.width as u64, GRID_SIZE.height as u64);
-const BOARD_MASK: u64 = BOTTOM_ROW_MASK * ((1 << GRID_SIZE.height)-1);
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum Player { First, Second }
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum Status {
+	Ongoing,
+	Won(Player),
+	Draw,
+}
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
-pub struct Position {
-	player_mask: u64,
-	pieces_mask: u64,
+pub struct Position<B: Bits = u64> {
+	player_mask: B,
+	pieces_mask: B,
 	pub move_count: u8,
+	rules: Rules,
 }
 
 impl Position {
-	pub fn max_moves() -> u8 {
-		GRID_SIZE.height * GRID_SIZE.width
-	}
 	pub fn new_empty() -> Position {
-		Position { player_mask: 0, pieces_mask: 0, move_count: 0 }
+		Position::with_rules(CONNECT_FOUR)
+	}
+}
+
+impl<B: Bits> Position<B> {
+	// Panics if the grid does not fit in `B` or the alignment is out of
+	// range, see `Rules::check`.
+	pub fn with_rules(rules: Rules) -> Position<B> {
+		if let Err(err) = rules.check::<B>() { panic!("{}", err) }
+		Position { player_mask: B::ZERO, pieces_mask: B::ZERO, move_count: 0, rules }
+	}
+
+	// Four in a row on a grid of the given size.
+	pub fn with_size(size: GridSize) -> Position<B> {
+		Position::with_rules(Rules { size, alignment: 4 })
+	}
+
+	pub fn from_moves(size: GridSize, moves: &str) -> Result<Position<B>, PositionParseError> {
+		Position::with_size(size).play_moves(moves)
+	}
+
+	// Plays a sequence of moves from this position, see `GridSize::column_name`.
+	pub fn play_moves(self, moves: &str) -> Result<Position<B>, PositionParseError> {
+		let mut pos = self;
+		for (index, character) in moves.chars().enumerate() {
+			let column = pos.size().column_number(character)
+				.ok_or(PositionParseError::InvalidCharacter { index, character })?;
+			if column == 0 || column > pos.size().width as u32 {
+				return Err(PositionParseError::ColumnOutOfRange { index, column })
+			}
+			let mov = column as u8 - 1;
+			if pos.winner().is_some() { return Err(PositionParseError::GameOver { index }) }
+			if !pos.can_play(mov) { return Err(PositionParseError::ColumnFull { index, column: mov + 1 }) }
+
+			pos = pos.next(mov);
+		}
+		Ok(pos)
+	}
+
+	// Reads a board drawn row by row from the top, `X` for the first
+	// player, `O` for the second one and `.` for empty cells, optionally
+	// followed by `X to move` or `O to move`, see `Display`. The player to
+	// move follows from the number of pieces, the marker is only checked.
+	pub fn from_diagram(rules: Rules, diagram: &str) -> Result<Position<B>, DiagramParseError> {
+		rules.check::<B>().map_err(DiagramParseError::Rules)?;
+		let size = rules.size;
+		let mut lines: Vec<&str> = diagram.trim().lines().map(str::trim).collect();
+		let marker = match lines.last() {
+			Some(&"X to move") => Some(Player::First),
+			Some(&"O to move") => Some(Player::Second),
+			_ => None,
+		};
+		if marker.is_some() { lines.pop(); }
+
+		if lines.len() != size.height as usize {
+			return Err(DiagramParseError::WrongSize { line: lines.len().min(size.height as usize) + 1 })
+		}
+		let mut first = Bitboard::empty(size);
+		let mut second = Bitboard::empty(size);
+		for (index, line) in lines.iter().enumerate() {
+			if line.chars().count() != size.width as usize {
+				return Err(DiagramParseError::WrongSize { line: index + 1 })
+			}
+			let row = size.height - 1 - index as u8;
+			for (col, character) in line.chars().enumerate() {
+				let cell = Bitboard::cell(size, col as u8, row);
+				match character {
+					'X' | 'x' => first = first | cell,
+					'O' | 'o' => second = second | cell,
+					'.' => (),
+					_ => return Err(DiagramParseError::InvalidCharacter { line: index + 1, column: col + 1, character }),
+				}
+			}
+		}
+
+		let pieces = first | second;
+		// A piece right above an empty cell.
+		if let Some((col, row)) = (pieces & !pieces.shift(Direction::Up, 1) & !Bitboard::bottom_row(size)).cells().next() {
+			return Err(DiagramParseError::FloatingPiece { line: (size.height - row) as usize, column: col as usize + 1 })
+		}
+		Position::from_pieces(rules, first, second, marker)
+	}
+
+	// Position with the given pieces of each player, which must not float.
+	// `marker` is the side to move if known, to check it.
+	fn from_pieces(
+		rules: Rules,
+		first: Bitboard<B>,
+		second: Bitboard<B>,
+		marker: Option<Player>,
+	) -> Result<Position<B>, DiagramParseError> {
+		let (first_count, second_count) = (first.count_ones(), second.count_ones());
+		let to_move = match first_count.checked_sub(second_count) {
+			Some(0) => Player::First,
+			Some(1) => Player::Second,
+			_ => return Err(DiagramParseError::PieceCount { first: first_count, second: second_count }),
+		};
+		if marker.is_some_and(|marker| marker != to_move) {
+			return Err(DiagramParseError::SideToMove { expected: to_move })
+		}
+
+		let position = Position {
+			player_mask: if to_move == Player::First { first.bits() } else { second.bits() },
+			pieces_mask: (first | second).bits(),
+			move_count: (first_count + second_count) as u8,
+			rules,
+		};
+		// The game would have stopped as soon as they won.
+		if position.check_alignment(position.player_mask) {
+			return Err(DiagramParseError::UnreachableWin { player: to_move })
+		}
+		// Likewise, some piece at the top of a column must complete every
+		// alignment of the last player, as their last move.
+		let last = position.opponent();
+		if position.check_alignment(last.bits()) {
+			let size = rules.size;
+			let tops = last & !position.pieces().shift(Direction::Down, 1);
+			let completed = tops.cells()
+				.any(|(col, row)| !position.check_alignment((last ^ Bitboard::cell(size, col, row)).bits()));
+			if !completed {
+				return Err(DiagramParseError::UnreachableWin { player: position.last_player().unwrap() })
+			}
+		}
+		Ok(position)
+	}
+
+	// One line, the same for every sequence of moves reaching this
+	// position: each column from the left, separated by `/`, lists its
+	// pieces from the bottom, with a count before those repeated, then
+	// comes the side to move. E.g. `//2O/3X/O// X` after 434345.
+	pub fn to_notation(self) -> String {
+		let (first, second) = match self.side_to_move() {
+			Player::First => (self.player(), self.opponent()),
+			Player::Second => (self.opponent(), self.player()),
+		};
+		let columns: Vec<String> = (0..self.size().width).map(|col| {
+			let mut column = String::new();
+			let mut cells = (0..self.size().height)
+				.map_while(|row| match (first.contains(col, row), second.contains(col, row)) {
+					(true, _) => Some('X'),
+					(_, true) => Some('O'),
+					_ => None,
+				})
+				.peekable();
+			while let Some(piece) = cells.next() {
+				let mut count = 1;
+				while cells.next_if_eq(&piece).is_some() { count += 1 }
+				if count > 1 { column.push_str(&count.to_string()) }
+				column.push(piece);
+			}
+			column
+		}).collect();
+		format!("{} {}", columns.join("/"), if self.side_to_move() == Player::First { 'X' } else { 'O' })
+	}
+
+	// Reads `to_notation`, counts of 1 and lowercase pieces are accepted.
+	pub fn from_notation(rules: Rules, notation: &str) -> Result<Position<B>, NotationParseError> {
+		rules.check::<B>().map_err(NotationParseError::Rules)?;
+		let size = rules.size;
+		let (board, side) = notation.trim().rsplit_once(' ').ok_or(NotationParseError::MissingSideToMove)?;
+		let marker = match side {
+			"X" | "x" => Player::First,
+			"O" | "o" => Player::Second,
+			_ => return Err(NotationParseError::MissingSideToMove),
+		};
+
+		let columns: Vec<&str> = board.split('/').collect();
+		if columns.len() != size.width as usize {
+			return Err(NotationParseError::WrongColumnCount { columns: columns.len() })
+		}
+		let mut first = Bitboard::empty(size);
+		let mut second = Bitboard::empty(size);
+		let mut index = 0;
+		for (col, column) in columns.iter().enumerate() {
+			let mut row = 0u32;
+			// Index where the count starts, and its value.
+			let mut count = None;
+			for character in column.chars() {
+				let piece = match character {
+					'0'..='9' => {
+						let (start, value) = count.unwrap_or((index, 0u32));
+						count = Some((start, value.saturating_mul(10).saturating_add(character.to_digit(10).unwrap())));
+						index += 1;
+						continue
+					},
+					'X' | 'x' => &mut first,
+					'O' | 'o' => &mut second,
+					_ => return Err(NotationParseError::InvalidCharacter { index, character }),
+				};
+				let (start, repeat) = count.take().unwrap_or((index, 1));
+				if repeat == 0 { return Err(NotationParseError::InvalidCharacter { index: start, character: '0' }) }
+				if repeat > size.height as u32 - row {
+					return Err(NotationParseError::ColumnTooHigh { column: col + 1 })
+				}
+				for _ in 0..repeat {
+					*piece = *piece | Bitboard::cell(size, col as u8, row as u8);
+					row += 1;
+				}
+				index += 1;
+			}
+			// A count must be followed by a piece.
+			if count.is_some() {
+				return Err(NotationParseError::InvalidCharacter { index, character: '/' })
+			}
+			index += 1;
+		}
+
+		Position::from_pieces(rules, first, second, Some(marker)).map_err(NotationParseError::Board)
+	}
+
+	// The player whose turn it is, even once the game is over.
+	pub fn side_to_move(&self) -> Player {
+		if self.move_count.is_multiple_of(2) { Player::First } else { Player::Second }
+	}
+
+	pub fn rules(&self) -> Rules {
+		self.rules
+	}
+
+	pub fn size(&self) -> GridSize {
+		self.rules.size
+	}
+
+	pub fn max_moves(&self) -> u8 {
+		self.size().height * self.size().width
 	}
 
 	pub fn wins(&self, col: u8) -> bool {
-		let mut mask =  self.player_mask;
-        mask |= (self.pieces_mask + Position::bottom_mask(col)) & Position::column_mask(col);
-		return Position::check_alignment(mask);
+		let mut mask = self.player_mask;
+		mask |= (self.pieces_mask + self.bottom_mask(col)) & self.column_mask(col).bits();
+		self.check_alignment(mask)
 	}
 
-    pub fn can_win(&self) -> bool {
-		self.possible_moves().any(|mov|self.wins(mov))
-    }
+	pub fn can_win(&self) -> bool {
+		self.possible_moves().any(|mov| self.wins(mov))
+	}
 
+	// The grid is full, see `status` to know whether someone won.
 	pub fn is_terminal(&self) -> bool {
-		return self.move_count == GRID_SIZE.width * GRID_SIZE.height;
+		self.move_count == self.max_moves()
+	}
+
+	// The player who made the last move, if any.
+	fn last_player(&self) -> Option<Player> {
+		match self.move_count {
+			0 => None,
+			count if count % 2 == 1 => Some(Player::First),
+			_ => Some(Player::Second),
+		}
+	}
+
+	// Only the last move can have aligned pieces, as no move is played
+	// after a win.
+	pub fn winner(&self) -> Option<Player> {
+		if self.check_alignment(self.player_mask ^ self.pieces_mask) { self.last_player() } else { None }
+	}
+
+	pub fn status(&self) -> Status {
+		match self.winner() {
+			Some(player) => Status::Won(player),
+			None if self.is_terminal() => Status::Draw,
+			None => Status::Ongoing,
+		}
 	}
 
 	pub fn can_play(&self, column: u8) -> bool {
-		return (self.pieces_mask & Position::top_mask(column)) == 0;
+		(self.pieces_mask & self.top_mask(column)) == B::ZERO
 	}
 
 	pub fn possible_moves(&self) -> impl Iterator<Item=u8> + '_ {
-		return (0..GRID_SIZE.width).filter(|x| self.can_play(*x));
+		(0..self.size().width).filter(|x| self.can_play(*x))
 	}
 
 	// Must be called on a playable move, see `can_play`.
-	pub fn next(&self, column: u8) -> Position {
+	pub fn next(&self, column: u8) -> Position<B> {
 		Position {
 			player_mask: self.player_mask ^ self.pieces_mask,
-			pieces_mask: self.pieces_mask | (self.pieces_mask + Position::bottom_mask(column)),
-			move_count: self.move_count + 1
+			pieces_mask: self.pieces_mask | (self.pieces_mask + self.bottom_mask(column)),
+			move_count: self.move_count + 1,
+			rules: self.rules,
 		}
 	}
 
-	pub fn key(&self) -> u64 {
+	pub fn key(&self) -> B {
 		self.player_mask + self.pieces_mask
 	}
 
-	fn check_alignment(mask: u64) -> bool {
-		let factors = [
-            1,                    // vertical
-            GRID_SIZE.height + 1, // horizontal
-            GRID_SIZE.height + 2, // diag 1
-            GRID_SIZE.height,     // diag 2
-        ];
+	// Left-right reflection of the position, the outcome is the same.
+	pub fn mirror(&self) -> Position<B> {
+		Position {
+			player_mask: self.mirror_mask(self.player_mask),
+			pieces_mask: self.mirror_mask(self.pieces_mask),
+			..*self
+		}
+	}
+
+	// Column matching `col` in the mirrored position.
+	pub fn mirror_column(&self, col: u8) -> u8 {
+		self.size().width - 1 - col
+	}
 
-        for factor in factors {
-            let m = mask & (mask >> factor);
-            if (m & (m >> (2 * factor))) != 0 {
-                return true;
-            }
-        }
+	// Same key for a position and its mirror, see `mirror_column` to
+	// translate moves when `canonical_key() != key()`.
+	pub fn canonical_key(&self) -> B {
+		self.key().min(self.mirror().key())
+	}
 
-        return false;
+	fn mirror_mask(&self, mask: B) -> B {
+		let mask = Bitboard::new(mask, self.size());
+		let width = self.size().width;
+		(0..width).fold(Bitboard::empty(self.size()), |mirrored, col| {
+			let column = mask.shift(Direction::Left, col as u32).column(0);
+			mirrored | column.shift(Direction::Right, (width - 1 - col) as u32)
+		}).bits()
+	}
+
+	fn check_alignment(&self, mask: B) -> bool {
+		let alignment = self.rules.alignment as u32;
+		let mask = Bitboard::new(mask, self.size());
+
+		let aligned = |direction| {
+			// Doubles the length of the alignments kept in `m` as long as
+			// possible, then completes with an overlapping shift.
+			let mut m = mask;
+			let mut length = 1;
+			while 2 * length <= alignment {
+				m = m & m.shift(direction, length);
+				length *= 2;
+			}
+			if length < alignment {
+				m = m & m.shift(direction, alignment - length);
+			}
+			!m.is_empty()
+		};
+
+		aligned(Direction::Down)        // vertical
+			|| aligned(Direction::Left)     // horizontal
+			|| aligned(Direction::DownLeft) // diag 1
+			|| aligned(Direction::UpLeft)   // diag 2
 	}
 
 	// return a bitmask containg a single 1 corresponding to the top cel of a given column
-	fn top_mask(col: u8) -> u64 {
-		return 1 << (GRID_SIZE.height - 1) << (col * (GRID_SIZE.height+1));
+	fn top_mask(&self, col: u8) -> B {
+		Bitboard::<B>::cell(self.size(), col, self.size().height - 1).bits()
 	}
 
 	// return a bitmask containg a single 1 corresponding to the bottom cell of a given column
-	fn bottom_mask(col: u8) -> u64 {
-		return 1 << (col*(GRID_SIZE.height+1));
+	fn bottom_mask(&self, col: u8) -> B {
+		Bitboard::<B>::cell(self.size(), col, 0).bits()
 	}
 
 	// return a bitmask 1 on all the cells of a given column
-	fn column_mask(col: u8) -> u64 {
-		return ((1 << GRID_SIZE.height)-1) << col*(GRID_SIZE.height+1);
+	pub fn column_mask(&self, col: u8) -> Bitboard<B> {
+		Bitboard::column_mask(self.size(), col)
+	}
+
+	// return a bitmask 1 on all the cells of the grid
+	fn board_mask(&self) -> Bitboard<B> {
+		Bitboard::full(self.size())
+	}
+
+	// Pieces of the player to move.
+	pub fn player(&self) -> Bitboard<B> {
+		Bitboard::new(self.player_mask, self.size())
+	}
+
+	pub fn opponent(&self) -> Bitboard<B> {
+		Bitboard::new(self.player_mask ^ self.pieces_mask, self.size())
+	}
+
+	pub fn pieces(&self) -> Bitboard<B> {
+		Bitboard::new(self.pieces_mask, self.size())
 	}
 
-	fn winning_position_mask(&self) -> u64 {
-		Position::compute_winning_position(self.player_mask, self.pieces_mask)
+	fn winning_position_mask(&self) -> Bitboard<B> {
+		self.compute_winning_position(self.player(), self.pieces())
+	}
+
+	fn opponent_winning_position_mask(&self) -> Bitboard<B> {
+		self.compute_winning_position(self.opponent(), self.pieces())
+	}
+
+	// return a bitmask 1 on the lowest empty cell of each column
+	fn possible_mask(&self) -> Bitboard<B> {
+		let bottom = Bitboard::bottom_row(self.size()).bits();
+		Bitboard::new(self.pieces_mask + bottom, self.size()) & self.board_mask()
+	}
+
+	// Cells of the moves that do not let the opponent win right away, see
+	// `column_mask`. Empty if every move loses, which happens when the
+	// opponent has two threats or one right above another. Only makes
+	// sense if the player to move cannot win, see `can_win`.
+	pub fn possible_non_losing_moves(&self) -> Bitboard<B> {
+		let mut possible = self.possible_mask();
+		let opponent_win = self.opponent_winning_position_mask();
+		let forced = possible & opponent_win;
+		if !forced.is_empty() {
+			// More than one threat to block.
+			if forced.count_ones() > 1 { return Bitboard::empty(self.size()) }
+			possible = forced;
+		}
+		// Never play right below an opponent threat.
+		possible & !opponent_win.shift(Direction::Down, 1)
 	}
 
 	pub fn move_score(&self, mov: u8) -> u64 {
-		let next_pieces_mask = self.pieces_mask | (self.pieces_mask + Position::bottom_mask(mov));
-		Position::pop_count(
-			Position::compute_winning_position(
-				(self.player_mask ^ self.pieces_mask) ^ next_pieces_mask,
-				next_pieces_mask
-			)
-		)
+		let next_pieces = self.pieces_mask | (self.pieces_mask + self.bottom_mask(mov));
+		let player = Bitboard::new((self.player_mask ^ self.pieces_mask) ^ next_pieces, self.size());
+		self.compute_winning_position(player, Bitboard::new(next_pieces, self.size())).count_ones() as u64
 	}
 
-	fn compute_winning_position(player_mask: u64, pieces_mask: u64) -> u64 {
+	// Empty cells completing an alignment for the player to move, minus
+	// those completing one for the opponent: a rough idea of who is ahead.
+	pub fn threat_balance(&self) -> i8 {
+		let own = self.winning_position_mask().count_ones();
+		let opponent = self.opponent_winning_position_mask().count_ones();
+		own as i8 - opponent as i8
+	}
+
+	fn compute_winning_position(&self, player: Bitboard<B>, pieces: Bitboard<B>) -> Bitboard<B> {
+		let alignment = self.rules.alignment as u32;
+
 		// vertical;
-		let mut r: u64 = (player_mask << 1) & (player_mask << 2) & (player_mask << 3);
-
-		//horizontal
-		let mut p: u64 = (player_mask << (GRID_SIZE.height+1)) & (player_mask << (2*(GRID_SIZE.height+1)));
-		r |= p & (player_mask << (3*(GRID_SIZE.height+1)));
-		r |= p & (player_mask >> (GRID_SIZE.height+1));
-		p = (player_mask >> (GRID_SIZE.height+1)) & (player_mask >> (2*(GRID_SIZE.height+1)));
-		r |= p & (player_mask << (GRID_SIZE.height+1));
-		r |= p & (player_mask >> (3*(GRID_SIZE.height+1)));
-
-		//diagonal 1
-		p = (player_mask << GRID_SIZE.height) & (player_mask << (2*GRID_SIZE.height));
-		r |= p & (player_mask << (3*GRID_SIZE.height));
-		r |= p & (player_mask >> GRID_SIZE.height);
-		p = (player_mask >> GRID_SIZE.height) & (player_mask >> (2*GRID_SIZE.height));
-		r |= p & (player_mask << GRID_SIZE.height);
-		r |= p & (player_mask >> (3*GRID_SIZE.height));
-
-		//diagonal 2
-		p = (player_mask << (GRID_SIZE.height+2)) & (player_mask << (2*(GRID_SIZE.height+2)));
-		r |= p & (player_mask << (3*(GRID_SIZE.height+2)));
-		r |= p & (player_mask >> (GRID_SIZE.height+2));
-		p = (player_mask >> (GRID_SIZE.height+2)) & (player_mask >> (2*(GRID_SIZE.height+2)));
-		r |= p & (player_mask << (GRID_SIZE.height+2));
-		r |= p & (player_mask >> (3*(GRID_SIZE.height+2)));
-
-		return r & (BOARD_MASK ^ pieces_mask);
-	}
-
-	const fn pop_count(mut mask: u64) -> u64 {
-		let mut c = 0;
-		while mask != 0 {
-			c+=1;
-			mask &= mask - 1;
-		}
-		c
+		let mut r = (1..alignment).fold(!Bitboard::empty(self.size()), |r, i| r & player.shift(Direction::Up, i));
+
+		// Cells completing an alignment along a line, called once per
+		// direction so that the shifts are known at compile time.
+		let mut line = |backward, forward| {
+			// after[i] marks cells followed by i aligned pieces.
+			let mut after = [!B::ZERO; MAX_ALIGNMENT as usize];
+			for i in 1..alignment {
+				after[i as usize] = after[i as usize - 1] & player.shift(backward, i).bits();
+			}
+			// Cells preceded by `before` aligned pieces and followed by the others.
+			let mut before = !B::ZERO;
+			for i in 0..alignment {
+				if i > 0 { before &= player.shift(forward, i).bits() }
+				r = r | Bitboard::new(before & after[(alignment - 1 - i) as usize], self.size());
+			}
+		};
+		line(Direction::Left, Direction::Right);              // horizontal
+		line(Direction::UpLeft, Direction::DownRight);        // diagonal 2
+		line(Direction::DownLeft, Direction::UpRight);        // diagonal 1
+
+		r & (self.board_mask() ^ pieces)
 	}
 }
 
@@ -196,7 +582,7 @@ mod tests {
 	#[test]
 	fn check_alignment() {
 		assert!(
-			!Position::check_alignment(Mask::from("
+			!Position::new_empty().check_alignment(Bitboard::from_diagram(GRID_SIZE, "
 				0000000
 				1100001
 				0100000
@@ -204,32 +590,377 @@ mod tests {
 				1110111
 				1011000
 				1100100
-			").into())
+			").unwrap().bits())
 		)
 	}
+
+	#[test]
+	fn other_sizes() {
+		let small = GridSize::new(6, 5);
+		assert!(Position::<u64>::from_moves(small, "112233").unwrap().wins(3), "Horizontal 6x5");
+		assert!(!Position::<u64>::from_moves(small, "11111").unwrap().can_play(0), "Full column 6x5");
+		assert!(Position::<u64>::from_moves(small, "1224333445").unwrap().wins(3), "Diagonal 6x5");
+
+		let large = GridSize::new(8, 7);
+		assert!(large.fits::<u64>());
+		assert!(Position::<u64>::from_moves(large, "878787").unwrap().wins(7), "Vertical 8x7");
+		assert!(Position::<u64>::from_moves(large, "667788").unwrap().wins(4), "Horizontal 8x7");
+		assert!(!Position::<u64>::from_moves(large, "1111111").unwrap().can_play(0), "Full column 8x7");
+	}
+
+	#[test]
+	fn u128_sizes() {
+		let size = GridSize::new(9, 7);
+		assert!(!size.fits::<u64>());
+		assert!(size.fits::<u128>());
+
+		assert!(Position::<u128>::from_moves(size, "989898").unwrap().wins(8), "Vertical 9x7");
+		assert!(Position::<u128>::from_moves(size, "778899").unwrap().wins(5), "Horizontal 9x7");
+		assert!(!Position::<u128>::from_moves(size, "9999999").unwrap().can_play(8), "Full column 9x7");
+	}
+
+	#[test]
+	fn connect_k() {
+		let connect = |alignment| Position::<u64>::with_rules(Rules::new(GRID_SIZE, alignment));
+
+		assert!(connect(3).play_moves("4343").unwrap().wins(3), "Vertical K=3");
+		assert!(connect(3).play_moves("1122").unwrap().wins(2), "Horizontal K=3");
+		assert!(connect(3).play_moves("122353").unwrap().wins(2), "Diagonal K=3");
+		assert!(!connect(3).play_moves("12").unwrap().can_win());
+
+		assert!(!connect(5).play_moves("343434").unwrap().wins(2), "Vertical K=5");
+		assert!(connect(5).play_moves("34343434").unwrap().wins(2), "Vertical K=5");
+		assert!(!connect(5).play_moves("112233").unwrap().wins(3), "Horizontal K=5");
+		assert!(connect(5).play_moves("11223344").unwrap().wins(4), "Horizontal K=5");
+
+		assert!(connect(6).play_moves("1122334455").unwrap().wins(5), "Horizontal K=6");
+		assert!(!connect(6).play_moves("1122334455").unwrap().wins(6), "Horizontal K=6");
+	}
+
+	#[test]
+	fn winning_position_matches_wins() {
+		for alignment in 1..=7 {
+			let mut pos = Position::<u64>::with_rules(Rules::new(GRID_SIZE, alignment));
+			for mov in "23163416124767223154467471272416755633".chars() {
+				// Once aligned, any move "wins" without filling a winning cell.
+				if pos.check_alignment(pos.player_mask) { break }
+
+				for col in pos.possible_moves() {
+					let cell = Bitboard::new(pos.pieces_mask + pos.bottom_mask(col), pos.size()) & pos.column_mask(col);
+					assert_eq!(
+						pos.wins(col),
+						!(pos.winning_position_mask() & cell).is_empty(),
+						"K={} column {} in\n{}", alignment, col, pos
+					);
+				}
+				pos = pos.next(mov.to_digit(10).unwrap() as u8 - 1);
+			}
+		}
+	}
+
+	#[test]
+	fn possible_non_losing_moves() {
+		let columns = |pos: Position| {
+			let mask = pos.possible_non_losing_moves();
+			(0..pos.size().width).filter(|col| !mask.column(*col).is_empty()).collect::<Vec<u8>>()
+		};
+		assert_eq!(columns(Position::new_empty()), (0..7).collect::<Vec<u8>>());
+		// The opponent threatens the bottom of column 4.
+		assert_eq!(columns(Position::try_from("11223").unwrap()), [3]);
+		// Two threats, on both sides.
+		assert_eq!(columns(Position::try_from("33445").unwrap()), []);
+
+		// Every move not letting the opponent win right away.
+		let mut positions = vec![Position::new_empty()];
+		for _ in 0..5 {
+			positions = positions.iter()
+				.flat_map(|pos| pos.possible_moves().filter(|col| !pos.wins(*col)).map(|col| pos.next(col)))
+				.collect();
+			for pos in positions.iter().filter(|pos| !pos.can_win()) {
+				let expected: Vec<u8> = pos.possible_moves().filter(|col| !pos.next(*col).can_win()).collect();
+				assert_eq!(columns(*pos), expected, "\n{}", pos);
+			}
+		}
+	}
+
+	#[test]
+	fn status() {
+		assert_eq!(Position::new_empty().status(), Status::Ongoing);
+		assert_eq!(Position::try_from("443355").unwrap().status(), Status::Ongoing);
+		assert_eq!(Position::try_from("443355").unwrap().next(5).status(), Status::Won(Player::First));
+		let pos = Position::try_from("1212123").unwrap();
+		assert_eq!(pos.next(1).winner(), Some(Player::Second));
+		assert_eq!(pos.next(0).winner(), None);
+
+		let full = Position::<u64>::from_moves(GridSize::new(2, 2), "1212").unwrap();
+		assert_eq!(full.status(), Status::Draw);
+		// A full grid may still be won by the last move.
+		let won = Position::<u64>::with_rules(Rules::new(GridSize::new(3, 3), 3)).play_moves("111223233").unwrap();
+		assert!(won.is_terminal());
+		assert_eq!(won.status(), Status::Won(Player::First));
+	}
+
+	#[test]
+	fn wide_grids() {
+		let size = GridSize::new(12, 4);
+		assert_eq!(size.column_name(0), 'a');
+		assert_eq!(size.column_name(11), 'l');
+		assert_eq!(GRID_SIZE.column_name(6), '7');
+		assert_eq!(GridSize::new(30, 1).column_name(26), '?');
+
+		assert!(Position::<u64>::from_moves(size, "ggiihh").unwrap().wins(9), "Horizontal 12x4");
+		assert_eq!(
+			Position::<u64>::from_moves(size, "abm"),
+			Err(PositionParseError::ColumnOutOfRange { index: 2, column: 13 })
+		);
+		// One notation per grid.
+		assert_eq!(
+			Position::<u64>::from_moves(size, "a2"),
+			Err(PositionParseError::InvalidCharacter { index: 1, character: '2' })
+		);
+		assert_eq!(
+			Position::try_from("4a4h"),
+			Err(PositionParseError::InvalidCharacter { index: 1, character: 'a' })
+		);
+		assert_eq!(GRID_SIZE.column_number('8'), Some(8));
+		assert_eq!(size.column_number('m'), Some(13));
+	}
+
+	// No string makes parsing panic, and every position parsed is one that
+	// can be reached by playing its moves.
+	#[test]
+	fn parse_random_strings() {
+		let sizes = [GRID_SIZE, GridSize::new(4, 4), GridSize::new(9, 6), GridSize::new(12, 4), GridSize::new(26, 3), GridSize::new(1, 5)];
+		let noise: Vec<char> = "0123456789abcdefghijklmnopqrstuvwxyzA -\u{e9}\n".chars().collect();
+		let mut rng = oorandom::Rand32::new(4);
+
+		for _ in 0..20_000 {
+			let size = sizes[rng.rand_range(0..sizes.len() as u32) as usize];
+			// Mostly valid columns, so that long games are played too.
+			let moves: Vec<char> = (0..rng.rand_range(0..50)).map(|_| match rng.rand_range(0..20) {
+				0 => noise[rng.rand_range(0..noise.len() as u32) as usize],
+				_ => size.column_name(rng.rand_range(0..size.width as u32) as u8),
+			}).collect();
+			let string: String = moves.iter().collect();
+
+			let pos = match Position::<u128>::from_moves(size, &string) {
+				Ok(pos) => pos,
+				Err(err) => {
+					let index = match err {
+						PositionParseError::InvalidCharacter { index, .. }
+						| PositionParseError::ColumnOutOfRange { index, .. }
+						| PositionParseError::ColumnFull { index, .. }
+						| PositionParseError::GameOver { index } => index,
+					};
+					// The moves before the faulty one are fine.
+					let before = Position::<u128>::from_moves(size, &moves[..index].iter().collect::<String>()).unwrap();
+					match err {
+						PositionParseError::InvalidCharacter { character, .. } =>
+							assert_eq!(size.column_number(character), None),
+						PositionParseError::ColumnOutOfRange { column, .. } =>
+							assert!(column == 0 || column > size.width as u32),
+						PositionParseError::ColumnFull { column, .. } => assert!(!before.can_play(column - 1)),
+						PositionParseError::GameOver { .. } => assert!(before.winner().is_some()),
+					}
+					continue
+				}
+			};
+
+			assert_eq!(pos.move_count as usize, moves.len(), "{:?}", string);
+			assert_eq!(pos.pieces().count_ones(), pos.move_count as u32);
+			assert_eq!(pos.opponent().count_ones(), pos.move_count.div_ceil(2) as u32);
+			assert_eq!(pos.player() & pos.opponent(), Bitboard::empty(size));
+			// Nothing outside the grid, and no piece floating.
+			assert_eq!(pos.pieces() & !Bitboard::full(size), Bitboard::empty(size));
+			for col in 0..size.width {
+				let column = pos.pieces().shift(Direction::Left, col as u32).column(0).bits();
+				assert_eq!(column & (column + 1), 0, "{:?}", string);
+			}
+
+			let replayed = moves.iter().fold(Position::<u128>::with_size(size), |replayed, c| {
+				replayed.next(size.column_number(*c).unwrap() as u8 - 1)
+			});
+			assert_eq!(pos, replayed);
+		}
+	}
+
+	#[test]
+	fn mirror() {
+		let pos = Position::try_from("1122334").unwrap();
+		assert_eq!(pos.mirror(), Position::try_from("7766554").unwrap());
+		assert_eq!(pos.mirror().mirror(), pos);
+		assert_eq!(pos.mirror_column(0), 6);
+		assert_eq!(pos.canonical_key(), pos.mirror().canonical_key());
+		assert_ne!(pos.key(), pos.mirror().key());
+
+		let symmetric = Position::try_from("443355").unwrap();
+		assert_eq!(symmetric.mirror(), symmetric);
+		assert_eq!(symmetric.canonical_key(), symmetric.key());
+
+		let size = GridSize::new(9, 7);
+		let pos = Position::<u128>::from_moves(size, "1929").unwrap();
+		assert_eq!(pos.mirror(), Position::<u128>::from_moves(size, "9181").unwrap());
+	}
+
+	#[test]
+	#[should_panic]
+	fn too_large() {
+		Position::<u64>::with_size(GridSize::new(9, 7));
+	}
 }
 
 impl TryFrom<String> for Position {
-	type Error = &'static str;
+	type Error = PositionParseError;
 	fn try_from(s: String) -> Result<Self, Self::Error>  {
-		if s.chars().any(|c|!c.is_digit(10)) {
-			return Err("Not a position.");
+		Position::from_moves(GRID_SIZE, &s)
+	}
+}
+
+impl TryFrom<&str> for Position {
+	type Error = PositionParseError;
+	fn try_from(s: &str) -> Result<Self, Self::Error> {
+		Position::from_moves(GRID_SIZE, s)
+	}
+}
+
+/// Why a sequence of moves is not a position. `index` counts characters
+/// from 0, columns are as written, from 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PositionParseError {
+	InvalidCharacter { index: usize, character: char },
+	ColumnOutOfRange { index: usize, column: u32 },
+	ColumnFull { index: usize, column: u8 },
+	// A move played once someone already won.
+	GameOver { index: usize },
+}
+
+impl std::fmt::Display for PositionParseError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			PositionParseError::InvalidCharacter { index, character } =>
+				write!(f, "invalid character {:?} at index {}", character, index),
+			PositionParseError::ColumnOutOfRange { index, column } =>
+				write!(f, "column {} out of the grid at move {}", column, index + 1),
+			PositionParseError::ColumnFull { index, column } =>
+				write!(f, "column {} already full at move {}", column, index + 1),
+			PositionParseError::GameOver { index } =>
+				write!(f, "move {} played after the game is over", index + 1),
 		}
+	}
+}
 
-		let mut pos = Position::new_empty();
-		for mov in s.chars().map(|c|c.to_digit(10).unwrap()-1) {
-			if !pos.can_play(mov as u8) { return Err("Position contains an invalid move.") }
+impl std::error::Error for PositionParseError {}
 
-			pos = pos.next(mov as u8);
+/// Why positions of some rules cannot be played, see `Rules::check`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RulesError {
+	// An empty grid, or one needing more than the `available` bits.
+	GridSize { width: u8, height: u8, available: u32 },
+	// Not between 1 and `MAX_ALIGNMENT`.
+	Alignment { alignment: u8 },
+}
+
+impl std::fmt::Display for RulesError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			RulesError::GridSize { width, height, available } => write!(
+				f,
+				"a {}x{} grid needs {} bits, only {} available",
+				width, height, GridSize::new(*width, *height).bits(), available
+			),
+			RulesError::Alignment { alignment } =>
+				write!(f, "alignment {} is not between 1 and {}", alignment, MAX_ALIGNMENT),
 		}
-		return Ok(pos);
 	}
 }
 
-impl TryFrom<&str> for Position {
-	type Error = &'static str;
-	fn try_from(s: &str) -> Result<Self, Self::Error> {
-		return Position::try_from(s.to_string());
+impl std::error::Error for RulesError {}
+
+/// Why a drawing is not a board, see `Position::from_diagram`. Lines and
+/// columns are counted from 1, from the top left of the drawing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiagramParseError {
+	// A line of the wrong width, or the first missing or extra one.
+	WrongSize { line: usize },
+	InvalidCharacter { line: usize, column: usize, character: char },
+	FloatingPiece { line: usize, column: usize },
+	// The first player plays first, so has as many pieces or one more.
+	PieceCount { first: u32, second: u32 },
+	// The marker does not match the number of pieces.
+	SideToMove { expected: Player },
+	// Pieces aligned before the last move, the game would have stopped.
+	UnreachableWin { player: Player },
+	Rules(RulesError),
+}
+
+impl std::fmt::Display for DiagramParseError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let name = |player: &Player| match player { Player::First => 'X', Player::Second => 'O' };
+		match self {
+			DiagramParseError::WrongSize { line } =>
+				write!(f, "the board does not match the grid size at line {}", line),
+			DiagramParseError::InvalidCharacter { line, column, character } =>
+				write!(f, "invalid character {:?} at line {} column {}", character, line, column),
+			DiagramParseError::FloatingPiece { line, column } =>
+				write!(f, "floating piece at line {} column {}", line, column),
+			DiagramParseError::PieceCount { first, second } =>
+				write!(f, "impossible piece count, {} X for {} O", first, second),
+			DiagramParseError::SideToMove { expected } =>
+				write!(f, "wrong side to move, {} should play", name(expected)),
+			DiagramParseError::UnreachableWin { player } =>
+				write!(f, "{} won before the last move", name(player)),
+			DiagramParseError::Rules(err) => err.fmt(f),
+		}
+	}
+}
+
+impl std::error::Error for DiagramParseError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			DiagramParseError::Rules(err) => Some(err),
+			_ => None,
+		}
+	}
+}
+
+/// Why a line is not a position, see `Position::from_notation`. `index`
+/// counts characters from 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotationParseError {
+	InvalidCharacter { index: usize, character: char },
+	WrongColumnCount { columns: usize },
+	// Columns are counted from 1.
+	ColumnTooHigh { column: usize },
+	MissingSideToMove,
+	// Pieces that cannot be reached, or the wrong side to move.
+	Board(DiagramParseError),
+	Rules(RulesError),
+}
+
+impl std::fmt::Display for NotationParseError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			NotationParseError::InvalidCharacter { index, character } =>
+				write!(f, "invalid character {:?} at index {}", character, index),
+			NotationParseError::WrongColumnCount { columns } =>
+				write!(f, "{} columns do not match the grid size", columns),
+			NotationParseError::ColumnTooHigh { column } =>
+				write!(f, "column {} higher than the grid", column),
+			NotationParseError::MissingSideToMove =>
+				write!(f, "missing side to move, X or O"),
+			NotationParseError::Board(err) => err.fmt(f),
+			NotationParseError::Rules(err) => err.fmt(f),
+		}
+	}
+}
+
+impl std::error::Error for NotationParseError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			NotationParseError::Board(err) => Some(err),
+			NotationParseError::Rules(err) => Some(err),
+			_ => None,
+		}
 	}
 }
 
@@ -244,6 +975,7 @@ mod tests_try_from_string {
 			player_mask: 0,
 			pieces_mask: 1,
 			move_count: 1,
+			rules: CONNECT_FOUR,
 		}));
 	}
 
@@ -253,6 +985,7 @@ mod tests_try_from_string {
 			player_mask: 2,
 			pieces_mask: 7,
 			move_count: 3,
+			rules: CONNECT_FOUR,
 		}));
 	}
 
@@ -261,7 +994,8 @@ mod tests_try_from_string {
 		assert_eq!(Position::try_from("22".to_string()), Ok(Position {
 			player_mask: 1 << (GRID_SIZE.height + 1),
 			pieces_mask: (1|2) << (GRID_SIZE.height + 1),
-			move_count: 2
+			move_count: 2,
+			rules: CONNECT_FOUR,
 		}));
 	}
 
@@ -269,149 +1003,239 @@ mod tests_try_from_string {
 	fn column_full() {
 		assert_eq!(
 			Position::try_from("6666666".to_string()),
-			Err("Position contains an invalid move.")
+			Err(PositionParseError::ColumnFull { index: 6, column: 6 })
 		)
 	}
 
 	#[test]
-	#[ignore = "not sure how this will be implemented"]
 	fn already_won() {
 		assert_eq!(
 			Position::try_from("44335522".to_string()),
-			Err("Position contains an invalid move.")
+			Err(PositionParseError::GameOver { index: 7 })
 		)
 	}
 
 	#[test]
 	fn invalid() {
-		assert_eq!(Position::try_from("hey".to_string()), Err("Not a position."));
+		assert_eq!(
+			Position::try_from("hey".to_string()),
+			Err(PositionParseError::InvalidCharacter { index: 0, character: 'h' })
+		);
+		assert_eq!(
+			Position::try_from("44 5".to_string()),
+			Err(PositionParseError::InvalidCharacter { index: 2, character: ' ' })
+		);
 	}
-}
-
-#[derive(Debug, PartialEq, Eq)]
-struct Mask(u64);
 
-impl std::fmt::Display for Mask {
-	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-		let s: Vec<char> = format!("{:#051b}", self.0).chars().rev().collect();
+	#[test]
+	fn out_of_range() {
+		assert_eq!(
+			Position::try_from("408".to_string()),
+			Err(PositionParseError::ColumnOutOfRange { index: 1, column: 0 })
+		);
+		assert_eq!(
+			Position::<u64>::from_moves(GridSize::new(5, 4), "156"),
+			Err(PositionParseError::ColumnOutOfRange { index: 2, column: 6 })
+		);
+	}
 
-		for row in (0..(GRID_SIZE.height + 1)).rev() {
-			writeln!(f, "{}", (0..GRID_SIZE.width).map(
-				|i| s[(row + i * (GRID_SIZE.height + 1)) as usize]
-			).fold(String::new(), |a, b| a + &b.to_string()))?;
-		}
-		return Ok(());
+	#[test]
+	fn error_display() {
+		assert_eq!(
+			PositionParseError::InvalidCharacter { index: 3, character: 'a' }.to_string(),
+			"invalid character 'a' at index 3"
+		);
+		assert_eq!(
+			PositionParseError::ColumnFull { index: 6, column: 6 }.to_string(),
+			"column 6 already full at move 7"
+		);
+		assert_eq!(
+			PositionParseError::GameOver { index: 7 }.to_string(),
+			"move 8 played after the game is over"
+		);
 	}
 }
 
-impl Into<u64> for Mask {
-	fn into(self) -> u64 { self.0 }
+// Draws the board as read by `Position::from_diagram`.
+impl<B: Bits> std::fmt::Display for Position<B> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let (first, second) = match self.side_to_move() {
+			Player::First => (self.player(), self.opponent()),
+			Player::Second => (self.opponent(), self.player()),
+		};
+		for row in (0..self.size().height).rev() {
+			writeln!(f, "{}", (0..self.size().width).map(|col| {
+				if first.contains(col, row) { 'X' } else if second.contains(col, row) { 'O' } else { '.' }
+			}).collect::<String>())?;
+		}
+		writeln!(f, "{} to move", if self.side_to_move() == Player::First { 'X' } else { 'O' })
+	}
 }
 
-impl From<u64> for Mask {
-	fn from(x: u64) -> Self { Mask(x) }
+#[test]
+fn test_display_position() {
+	assert_eq!(format!("{}", Position::<u64> {
+		player_mask: 2 << (GRID_SIZE.height + 1),
+		pieces_mask: 7 << (GRID_SIZE.height + 1),
+		move_count: 3,
+		rules: CONNECT_FOUR,
+	}),
+".......
+.......
+.......
+.X.....
+.O.....
+.X.....
+O to move
+");
 }
 
-impl From<&str> for Mask {
-	fn from(s: &str) -> Self {
-		let bidym_chars = s.trim()
-			.split("\n")
-			.map(|line|line.trim().chars().map(|c|c.to_digit(10).unwrap() as u64).collect::<Vec<u64>>())
-			.collect::<Vec<_>>();
-
-		assert_eq!(bidym_chars.len(), GRID_SIZE.width as usize);
-		assert_eq!(bidym_chars[0].len(), (GRID_SIZE.height + 1) as usize);
-
-		let mut rv = 0u64;
-		let mut pow = 0;
+#[cfg(test)]
+mod tests_diagram {
+	use super::*;
 
-		for i in 0..GRID_SIZE.width {
-			for j in 0..(GRID_SIZE.height + 1) {
-				rv |= bidym_chars[(GRID_SIZE.height - j) as usize][i as usize] << pow;
-				pow += 1;
-			}
+	#[test]
+	fn round_trip() {
+		for moves in ["", "4", "4453", "23163416124767223154467471272416755633"] {
+			let pos = Position::try_from(moves).unwrap();
+			assert_eq!(Position::from_diagram(CONNECT_FOUR, &pos.to_string()), Ok(pos), "{}", moves);
 		}
-
-		Mask(rv)
+		let size = GridSize::new(9, 7);
+		let pos = Position::<u128>::from_moves(size, "19293").unwrap();
+		assert_eq!(Position::from_diagram(Rules::new(size, 4), &pos.to_string()), Ok(pos));
 	}
-}
 
-#[test]
-fn test_mask_from_str() {
-	assert_eq!(
-		Mask((1 | 2 | 4) << (GRID_SIZE.height + 1)),
-		Mask::from("
-			0000000
-			0000000
-			0000000
-			0000000
-			0100000
-			0100000
-			0100000
-		")
-	);
-	assert_eq!(
-		Mask::from("
-			0000000
-			1100001
-			0100000
-			0011001
-			1110111
-			1011000
-			1100100
-		").to_string(),
-		"\
-			0000000\n\
-			1100001\n\
-		 	0100000\n\
-			0011001\n\
-			1110111\n\
-			1011000\n\
-			1100100\n\
-		"
-	)
-}
+	#[test]
+	fn from_diagram() {
+		let pos = Position::from_diagram(CONNECT_FOUR, "
+			.......
+			.......
+			.......
+			...X...
+			..OX...
+			..OXO..
+		").unwrap();
+		assert_eq!(pos, Position::try_from("434345").unwrap());
+		assert_eq!(pos.side_to_move(), Player::First);
+		// Lowercase pieces and the marker.
+		assert_eq!(
+			Position::from_diagram(CONNECT_FOUR, ".......\n.......\n.......\n.......\n.......\n...x...\nO to move"),
+			Ok(Position::try_from("4").unwrap())
+		);
+	}
 
+	#[test]
+	fn invalid_diagrams() {
+		let parse = |diagram: &str| Position::<u64>::from_diagram(CONNECT_FOUR, diagram);
+		let board = |last_rows: &str| format!(".......\n.......\n.......\n.......\n{}", last_rows);
 
-impl std::fmt::Display for Position {
-	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-		writeln!(f, "move_count: {:0>2}\n", self.move_count)?;
+		assert_eq!(parse(".......\n......."), Err(DiagramParseError::WrongSize { line: 3 }));
+		assert_eq!(parse(&board("........\n.......")), Err(DiagramParseError::WrongSize { line: 5 }));
+		assert_eq!(
+			parse(&board(".......\n...Y...")),
+			Err(DiagramParseError::InvalidCharacter { line: 6, column: 4, character: 'Y' })
+		);
+		assert_eq!(parse(&board("...X...\n.......")), Err(DiagramParseError::FloatingPiece { line: 5, column: 4 }));
+		assert_eq!(parse(&board(".......\n..OO...")), Err(DiagramParseError::PieceCount { first: 0, second: 2 }));
+		assert_eq!(parse(&board(".......\n..XX...")), Err(DiagramParseError::PieceCount { first: 2, second: 0 }));
+		assert_eq!(
+			parse(&board(".......\n...X...\nX to move")),
+			Err(DiagramParseError::SideToMove { expected: Player::Second })
+		);
+		assert_eq!(
+			parse(".......\n.......\n..O....\n..OX...\n..OX...\n.OOXXXX"),
+			Err(DiagramParseError::UnreachableWin { player: Player::Second })
+		);
+		// Won before the last move, which covered the four.
+		assert_eq!(
+			parse(".......\n.......\n.......\n.......\n.OXOO..\nOXXXX.."),
+			Err(DiagramParseError::UnreachableWin { player: Player::First })
+		);
+		// Won by the last move.
+		let won = parse(".......\n.......\n.......\n.......\n.OOO...\n.XXXX..").unwrap();
+		assert_eq!(won.status(), Status::Won(Player::First));
 
-		writeln!(f, "pieces_mask")?;
-		writeln!(f, "{}", Mask(self.pieces_mask))?;
-		writeln!(f, "player_mask")?;
-		writeln!(f, "{}", Mask(self.player_mask))?;
+		assert_eq!(
+			DiagramParseError::FloatingPiece { line: 5, column: 4 }.to_string(),
+			"floating piece at line 5 column 4"
+		);
 
-		Ok(())
+		// Rules no position can be played with.
+		let single = board(".......\n...X...");
+		for alignment in [0, 20] {
+			assert_eq!(
+				Position::<u64>::from_diagram(Rules::new(GRID_SIZE, alignment), &single),
+				Err(DiagramParseError::Rules(RulesError::Alignment { alignment }))
+			);
+		}
+		assert_eq!(
+			Position::<u64>::from_diagram(Rules::new(GridSize::new(9, 7), 4), ".........").unwrap_err().to_string(),
+			"a 9x7 grid needs 72 bits, only 64 available"
+		);
 	}
 }
 
-#[test]
-fn test_display_position() {
-	assert_eq!(format!("{}", Position {
-		player_mask: 2 << (GRID_SIZE.height + 1),
-		pieces_mask: 7 << (GRID_SIZE.height + 1),
-		move_count: 3
-	}),
-"move_count: 03
-
-pieces_mask
-0000000
-0000000
-0000000
-0000000
-0100000
-0100000
-0100000
-
-player_mask
-0000000
-0000000
-0000000
-0000000
-0000000
-0100000
-0000000
+#[cfg(test)]
+mod tests_notation {
+	use super::*;
 
-");
+	#[test]
+	fn to_notation() {
+		assert_eq!(Position::new_empty().to_notation(), "////// X");
+		assert_eq!(Position::try_from("434345").unwrap().to_notation(), "//2O/3X/O// X");
+		assert_eq!(Position::try_from("4444445").unwrap().to_notation(), "///XOXOXO/X// O");
+		// Canonical, whatever the order of the moves.
+		assert_eq!(Position::try_from("1234").unwrap().to_notation(), Position::try_from("3214").unwrap().to_notation());
+	}
+
+	#[test]
+	fn round_trip() {
+		let mut rng = oorandom::Rand32::new(23);
+		for size in [GRID_SIZE, GridSize::new(10, 10), GridSize::new(2, 3)] {
+			for _ in 0..200 {
+				let mut pos = Position::<u128>::with_size(size);
+				let length = rng.rand_range(0..size.width as u32 * size.height as u32);
+				for _ in 0..length {
+					if pos.status() != Status::Ongoing { break }
+					let moves: Vec<u8> = pos.possible_moves().collect();
+					pos = pos.next(moves[rng.rand_range(0..moves.len() as u32) as usize]);
+				}
+				let notation = pos.to_notation();
+				assert_eq!(Position::from_notation(Rules::new(size, 4), &notation), Ok(pos), "{}", notation);
+			}
+		}
+	}
+
+	#[test]
+	fn from_notation() {
+		let parse = |notation: &str| Position::<u64>::from_notation(CONNECT_FOUR, notation);
+		assert_eq!(parse("//oo/xxX/1O// x"), Ok(Position::try_from("434345").unwrap()));
+
+		assert_eq!(parse("//2O/3X/O//"), Err(NotationParseError::MissingSideToMove));
+		assert_eq!(parse("//2O/3X/O// Y"), Err(NotationParseError::MissingSideToMove));
+		assert_eq!(parse("//2O/3X/O/ X"), Err(NotationParseError::WrongColumnCount { columns: 6 }));
+		assert_eq!(parse("//2O/3Y/O// X"), Err(NotationParseError::InvalidCharacter { index: 6, character: 'Y' }));
+		assert_eq!(parse("//2O/3/XO// X"), Err(NotationParseError::InvalidCharacter { index: 6, character: '/' }));
+		assert_eq!(parse("//2O/7X/O// X"), Err(NotationParseError::ColumnTooHigh { column: 4 }));
+		assert_eq!(parse("//0X//// O"), Err(NotationParseError::InvalidCharacter { index: 2, character: '0' }));
+		assert_eq!(parse("//00X//// O"), Err(NotationParseError::InvalidCharacter { index: 2, character: '0' }));
+		assert_eq!(
+			Position::<u64>::from_notation(Rules::new(GridSize::new(9, 7), 4), "////////X O"),
+			Err(NotationParseError::Rules(RulesError::GridSize { width: 9, height: 7, available: 64 }))
+		);
+		assert_eq!(
+			Position::<u64>::from_notation(Rules::new(GRID_SIZE, 0), "X////// O"),
+			Err(NotationParseError::Rules(RulesError::Alignment { alignment: 0 }))
+		);
+		assert_eq!(parse("X4294967295X////// O"), Err(NotationParseError::ColumnTooHigh { column: 1 }));
+		assert_eq!(parse("99999999999X////// O"), Err(NotationParseError::ColumnTooHigh { column: 1 }));
+		assert_eq!(
+			parse("//2O/3X/O// O"),
+			Err(NotationParseError::Board(DiagramParseError::SideToMove { expected: Player::First }))
+		);
+		assert_eq!(
+			parse("//2O/3X/2O// X").unwrap_err().to_string(),
+			"impossible piece count, 3 X for 4 O"
+		);
+	}
 }
@@ -1,7 +1,11 @@
-use crate::position::Position;
-use crate::position;
+use std::sync::atomic::{AtomicBool, AtomicI8, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use std::collections::HashMap;
+use crate::book::Book;
+use crate::bitboard::{Bitboard, Bits};
+use crate::position::{Position, PositionParseError, Rules, Status, CONNECT_FOUR};
+use crate::transposition_table::{Bound, TableStats, TranspositionTable, DEFAULT_TABLE_SIZE};
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum Outcome {
@@ -15,187 +19,966 @@ impl From<i8> for Outcome {
 		if u > 0 { return Outcome::Win(u as u8); }
 		if u < 0 { return Outcome::Loose((-u) as u8); }
 
-		return Outcome::Draw;
+		Outcome::Draw
 	}
 }
 
+impl From<&Outcome> for i8 {
+	fn from(outcome: &Outcome) -> i8 {
+		match *outcome {
+			Outcome::Draw => 0,
+			Outcome::Win(x) => x as i8,
+			Outcome::Loose(x) => -(x as i8),
+		}
+	}
+}
+
+/// Limits of each search of a solver, unlimited by default. Once one is
+/// exceeded the search stops and returns the best move found so far.
+#[derive(Debug, Clone, Default)]
+pub struct Budget {
+	pub time: Option<Duration>,
+	pub nodes: Option<u128>,
+	// Stops the search once set, from any thread.
+	pub cancel: Option<Arc<AtomicBool>>,
+}
+
 pub struct Solver {
 	positions_checked: u128,
-	transposition_table: HashMap<u64, (u8, i8)>
+	transposition_table: TranspositionTable,
+	// Set when a depth-limited search evaluated a position heuristically.
+	depth_reached: bool,
+	budget: Budget,
+	deadline: Option<Instant>,
+	// Set once the budget is exceeded, the search then unwinds.
+	aborted: bool,
+	// Extra solvers of `solve_parallel_position`, one per thread.
+	helpers: Vec<Solver>,
+	// Shared with the helpers.
+	book: Option<Arc<Book>>,
 }
 
-type SolverResult = (u8, u128, Outcome);
+#[derive(Debug)]
+pub struct SolverResult {
+	pub best_move: u8,
+	pub positions_checked: u128,
+	pub outcome: Outcome,
+	pub table_stats: TableStats,
+	// The outcome is not proven: either a heuristic estimation, see
+	// `Solver::search_depth`, or the search ran out of budget, in which
+	// case the outcome is a lower bound of the actual one.
+	pub approximate: bool,
+	// Best moves of both players until the game ends, in the format of
+	// `Position::try_from`. Empty unless the score is exact.
+	pub principal_variation: String,
+}
 
-struct MoveScore(u8, (u64, u8));
+// Widest grid the solver can sort moves of, more than any key type holds.
+const MAX_WIDTH: usize = 64;
 
-impl std::cmp::PartialEq for MoveScore {
-    fn eq(&self, other: &Self) -> bool { self.1.eq(&other.1) }
+// Moves sorted by `Position::move_score` as they are added, without
+// allocating. Iterating gives the best ones first, the last added first
+// among equal scores.
+struct MoveSorter {
+	// `(move, score)` by increasing score, scores are at most 128 cells.
+	moves: [(u8, u8); MAX_WIDTH],
+	len: usize,
 }
 
-impl std::cmp::PartialOrd for MoveScore {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> { self.1.partial_cmp(&other.1) }
+impl MoveSorter {
+	// Playable columns intersecting `moves`, a mask of cells. The center
+	// ones come first among equal scores.
+	fn new<B: Bits>(pos: Position<B>, moves: Bitboard<B>) -> MoveSorter {
+		let mut sorter = MoveSorter { moves: [(0, 0); MAX_WIDTH], len: 0 };
+		let width = pos.size().width;
+		for i in (0..width).rev() {
+			// Columns from the center outwards, e.g. 3 2 4 1 5 0 6.
+			let col = if i % 2 == 1 { width / 2 - i.div_ceil(2) } else { width / 2 + i / 2 };
+			if pos.can_play(col) && !moves.column(col).is_empty() {
+				sorter.add(col, pos.move_score(col) as u8);
+			}
+		}
+		sorter
+	}
+
+	fn add(&mut self, mov: u8, score: u8) {
+		let mut i = self.len;
+		while i > 0 && self.moves[i - 1].1 > score {
+			self.moves[i] = self.moves[i - 1];
+			i -= 1;
+		}
+		self.moves[i] = (mov, score);
+		self.len += 1;
+	}
+
+	fn peek(&self) -> Option<u8> {
+		self.len.checked_sub(1).map(|last| self.moves[last].0)
+	}
 }
 
-impl std::cmp::Eq for MoveScore {}
+impl Iterator for MoveSorter {
+	type Item = u8;
 
-impl std::cmp::Ord for MoveScore {
-	fn cmp(&self, other: &Self) -> std::cmp::Ordering { self.1.cmp(&other.1) }
+	fn next(&mut self) -> Option<u8> {
+		let mov = self.peek()?;
+		self.len -= 1;
+		Some(mov)
+	}
 }
 
 impl Solver {
-	pub fn explain_outcome(position: Position, outcome: Outcome) -> String {
+	pub fn explain_outcome<B: Bits>(position: Position<B>, result: &SolverResult) -> String {
 		let end_in_x_moves = |x: u8|
-			(Position::max_moves() as i8 - position.move_count as i8) / 2 - x as i8 + 1;
-		match outcome {
+			(position.max_moves() as i8 - position.move_count as i8) / 2 - x as i8 + 1;
+		let outcome = match result.outcome {
 			Outcome::Draw => "draw".to_string(),
 			Outcome::Win(x) => format!("win in {} moves", end_in_x_moves(x)),
 			Outcome::Loose(x) => format!("loose in {} moves", end_in_x_moves(x))
+		};
+		match result.principal_variation.as_str() {
+			"" => outcome,
+			line => format!("{}: {}", outcome, line),
 		}
 	}
 
-	pub fn solve(position: Position) -> SolverResult {
+	pub fn solve<B: Bits>(position: Position<B>) -> SolverResult {
+		Solver::new().solve_position(position)
+	}
+
+	pub fn weakly_solve<B: Bits>(position: Position<B>) -> SolverResult {
+		Solver::new().weakly_solve_position(position)
+	}
+
+	pub fn solve_exact<B: Bits>(position: Position<B>) -> SolverResult {
+		Solver::new().solve_exact_position(position)
+	}
+
+	pub fn analyze<B: Bits>(position: Position<B>) -> Vec<Option<i8>> {
+		Solver::new().analyze_position(position)
+	}
+
+	pub fn search_depth<B: Bits>(position: Position<B>, depth: u8) -> SolverResult {
+		Solver::new().search_depth_position(position, depth)
+	}
+
+	pub fn new() -> Solver {
+		Solver::with_table_size(DEFAULT_TABLE_SIZE)
+	}
+
+	// `bytes` is the memory allowed for the transposition table.
+	pub fn with_table_size(bytes: usize) -> Solver {
+		Solver {
+			positions_checked: 0,
+			transposition_table: TranspositionTable::with_memory(CONNECT_FOUR, bytes),
+			depth_reached: false,
+			budget: Budget::default(),
+			deadline: None,
+			aborted: false,
+			helpers: Vec::new(),
+			book: None,
+		}
+	}
+
+	// Consults the opening book saved at `path` before searching, see
+	// `Solver::build_book`.
+	pub fn with_book<P: AsRef<std::path::Path>>(path: P) -> std::io::Result<Solver> {
 		let mut solver = Solver::new();
-		match solver.strongly_solve(position) {
-			(mov, outcome) => {
-				assert_ne!(mov, u8::MAX);
-				(mov, solver.positions_checked, outcome.into())
+		solver.set_book(Some(Arc::new(Book::load(path)?)));
+		Ok(solver)
+	}
+
+	// Lets a later run resume with what this one solved, for positions of
+	// the rules of the last search only.
+	pub fn save_table<P: AsRef<std::path::Path>>(&self, path: P) -> std::io::Result<()> {
+		self.transposition_table.save(path)
+	}
+
+	// Replaces the transposition table, its size becomes the saved one.
+	pub fn load_table<P: AsRef<std::path::Path>>(&mut self, path: P, rules: Rules) -> std::io::Result<()> {
+		self.transposition_table = TranspositionTable::load(path, rules)?;
+		Ok(())
+	}
+
+	// Returns the previous book.
+	pub fn set_book(&mut self, book: Option<Arc<Book>>) -> Option<Arc<Book>> {
+		std::mem::replace(&mut self.book, book)
+	}
+
+	// Solves every position reachable in up to `depth` moves, positions
+	// which can be won right away excepted. The deepest ones are solved
+	// first so that the others are found in the book being built.
+	pub fn build_book<B: Bits>(&mut self, rules: Rules, depth: u8) -> Book {
+		let mut levels = vec![vec![Position::<B>::with_rules(rules)]];
+		for _ in 0..depth {
+			let mut next = std::collections::HashMap::new();
+			for position in levels.last().unwrap() {
+				for mov in position.possible_moves().filter(|mov| !position.wins(*mov)) {
+					let child = position.next(mov);
+					if !child.is_terminal() { next.entry(child.canonical_key()).or_insert(child); }
+				}
 			}
+			levels.push(next.into_values().collect());
+		}
+
+		let budget = std::mem::take(&mut self.budget);
+		let previous = self.set_book(Some(Arc::new(Book::new(rules, depth))));
+		self.start(rules);
+		for level in levels.iter().rev() {
+			let scores: Vec<_> = level.iter()
+				.filter(|position| !position.can_win())
+				.map(|position| {
+					let (best_move, score) = self.exact_negamax(*position);
+					(*position, best_move, score)
+				})
+				.collect();
+			// Not shared while building.
+			Arc::get_mut(self.book.as_mut().unwrap()).unwrap().extend(scores);
 		}
+		self.budget = budget;
+		Arc::into_inner(self.set_book(previous).unwrap()).unwrap()
 	}
 
-	pub fn weakly_solve(position: Position) -> SolverResult {
-		let mut solver = Solver::new();
-		match solver.weakly_solve_(position) {
-			(mov, outcome) => {
-				// assert_ne!(mov, u8::MAX, "impossible best move");
-				(mov, solver.positions_checked, outcome.into())
+	// Applies to every following search.
+	pub fn set_budget(&mut self, budget: Budget) {
+		self.budget = budget;
+	}
+
+	// Forgets every position solved so far.
+	pub fn reset(&mut self) {
+		self.transposition_table.clear();
+		self.helpers.iter_mut().for_each(Solver::reset);
+	}
+
+	pub fn solve_position<B: Bits>(&mut self, position: Position<B>) -> SolverResult {
+		self.start(position.rules());
+		if let Some(result) = self.game_over_result(position) { return result }
+		let (mov, score) = self.negamax(position, i8::MIN + 1, i8::MAX - 1);
+		if self.aborted { return self.aborted_result(position, mov, score) }
+
+		assert_ne!(mov, u8::MAX);
+		let result = self.result(mov, score);
+		SolverResult { principal_variation: self.principal_variation(position, mov), ..result }
+	}
+
+	pub fn weakly_solve_position<B: Bits>(&mut self, position: Position<B>) -> SolverResult {
+		self.start(position.rules());
+		if let Some(result) = self.game_over_result(position) { return result }
+		let (mov, outcome) = self.negamax(position, -1,  1);
+		if self.aborted { return self.aborted_result(position, mov, outcome) }
+		// assert_ne!(mov, u8::MAX, "impossible best move");
+		self.result(mov, outcome)
+	}
+
+	// Same result as `solve_position`, but found by a binary search on the
+	// score where each step is a null-window search, which prunes much more
+	// than a full window. The transposition table is shared between steps.
+	pub fn solve_exact_position<B: Bits>(&mut self, position: Position<B>) -> SolverResult {
+		self.start(position.rules());
+		if let Some(result) = self.game_over_result(position) { return result }
+		let (best_mov, score) = self.exact_negamax(position);
+		let result = self.result(best_mov, score);
+		if self.aborted { return result }
+
+		SolverResult { principal_variation: self.principal_variation(position, best_mov), ..result }
+	}
+
+	// Exact score of every column, `None` for full ones and the ones not
+	// solved within the budget, or for all of them once the game is over.
+	// The best moves have the greatest score.
+	pub fn analyze_position<B: Bits>(&mut self, position: Position<B>) -> Vec<Option<i8>> {
+		self.start(position.rules());
+		if position.status() != Status::Ongoing { return vec![None; position.size().width as usize] }
+		let win_score = (position.max_moves() as i8 + 1 - position.move_count as i8) / 2;
+		(0..position.size().width).map(|col| {
+			if !position.can_play(col) { return None }
+			if position.wins(col) { return Some(win_score) }
+			let score = -self.exact_negamax(position.next(col)).1;
+			if self.aborted { None } else { Some(score) }
+		}).collect()
+	}
+
+	// Same outcome as `solve_position`, with the root moves split between
+	// `threads` solvers, each one having its own transposition table of the
+	// size of this one. A move is only solved exactly if it may be better
+	// than the best one found so far.
+	pub fn solve_parallel_position<B: Bits>(&mut self, position: Position<B>, threads: usize) -> SolverResult {
+		self.start(position.rules());
+		self.positions_checked = 1;
+		if let Some(result) = self.game_over_result(position) { return result }
+
+		let win_score = (position.max_moves() as i8 + 1 - position.move_count as i8) / 2;
+		if let Some(mov) = position.possible_moves().find(|mov| position.wins(*mov)) {
+			let result = self.result(mov, win_score);
+			return SolverResult { principal_variation: position.size().column_name(mov).to_string(), ..result }
+		}
+
+		let moves: Vec<u8> = MoveSorter::new(position, Bitboard::full(position.size())).collect();
+		let lower_bound = -((position.max_moves() - position.move_count) as i8) / 2;
+		let next = AtomicUsize::new(0);
+		let best = AtomicI8::new(lower_bound - 1);
+
+		// The nodes budget is split between the threads, this one also
+		// searches what is left of the division.
+		let threads = threads.max(1);
+		let budget = self.budget.clone();
+		let share = budget.nodes.map(|nodes| nodes / threads as u128);
+		self.budget.nodes = budget.nodes.zip(share).map(|(nodes, share)| nodes - share * (threads as u128 - 1));
+
+		let mut helpers = std::mem::take(&mut self.helpers);
+		helpers.resize_with(threads - 1, || Solver::with_table_size(self.transposition_table.memory()));
+		let scores = std::thread::scope(|scope| {
+			let workers: Vec<_> = helpers.iter_mut().map(|helper| {
+				helper.budget = Budget { nodes: share, ..budget.clone() };
+				helper.book = self.book.clone();
+				helper.start(position.rules());
+				scope.spawn(|| helper.solve_root_moves(position, &moves, &next, &best))
+			}).collect();
+			let mut scores = self.solve_root_moves(position, &moves, &next, &best);
+			for worker in workers { scores.extend(worker.join().unwrap()) }
+			scores
+		});
+		self.budget = budget;
+
+		for helper in &helpers {
+			self.positions_checked += helper.positions_checked;
+			self.aborted |= helper.aborted;
+		}
+		let mut result = self.result(u8::MAX, lower_bound);
+		for helper in &helpers {
+			let stats = helper.transposition_table.stats();
+			result.table_stats.hits += stats.hits;
+			result.table_stats.misses += stats.misses;
+			result.table_stats.collisions += stats.collisions;
+		}
+		self.helpers = helpers;
+
+		// Among the best moves, the first one in the search order is kept.
+		let best_score = scores.iter().map(|(_, score, _)| *score).max();
+		let chosen = scores.into_iter()
+			.filter(|(_, score, line)| Some(*score) == best_score && !line.is_empty())
+			.min_by_key(|(index, _, _)| *index);
+		match chosen {
+			Some((index, score, line)) => SolverResult {
+				best_move: moves[index],
+				outcome: score.into(),
+				principal_variation: if self.aborted { String::new() } else { line },
+				..result
+			},
+			// Out of budget before any move was solved.
+			None => SolverResult { best_move: Solver::first_move(position), ..result },
+		}
+	}
+
+	// Solves `moves[next]` while there are some left, giving their index,
+	// score and principal variation. The score is only an upper bound and
+	// the line empty for the ones not better than `best`.
+	fn solve_root_moves<B: Bits>(
+		&mut self,
+		position: Position<B>,
+		moves: &[u8],
+		next: &AtomicUsize,
+		best: &AtomicI8
+	) -> Vec<(usize, i8, String)> {
+		let mut scores = Vec::new();
+		loop {
+			let index = next.fetch_add(1, Ordering::Relaxed);
+			if index >= moves.len() { return scores }
+
+			let mov = moves[index];
+			let known = best.load(Ordering::SeqCst);
+			let (reply, opponent_score) = self.bounded_exact_negamax(position.next(mov), -known);
+			if self.aborted { return scores }
+
+			let score = -opponent_score;
+			if score <= known {
+				scores.push((index, score, String::new()));
+				continue
 			}
+			best.fetch_max(score, Ordering::SeqCst);
+			let line = format!("{}{}", position.size().column_name(mov), self.principal_variation(position.next(mov), reply));
+			scores.push((index, score, line));
 		}
 	}
 
-	pub fn solve_str(position: String) -> Result<SolverResult, &'static str> {
-		match Position::try_from(position) {
-			Result::Ok(position) => Ok(Solver::solve(position)),
-			Result::Err(err) => Err(err)
+	fn exact_negamax<B: Bits>(&mut self, position: Position<B>) -> (u8, i8) {
+		self.bounded_exact_negamax(position, i8::MAX)
+	}
+
+	// Same as `exact_negamax` for scores below `max`, otherwise the score
+	// returned is only a lower bound, at least `max`.
+	fn bounded_exact_negamax<B: Bits>(&mut self, position: Position<B>, max: i8) -> (u8, i8) {
+		if position.is_terminal() { return (0, 0) }
+
+		let mut min = -((position.max_moves() - position.move_count) as i8) / 2;
+		let mut max = max.min((position.max_moves() + 1 - position.move_count) as i8 / 2);
+		let mut best_mov = u8::MAX;
+
+		while min < max {
+			// Searching around 0 first helps to find out quickly who wins.
+			let mut med = min + (max - min) / 2;
+			if med <= 0 && min / 2 < med { med = min / 2 }
+			else if med >= 0 && max / 2 > med { med = max / 2 }
+
+			let (mov, score) = self.negamax(position, med, med + 1);
+			// `min` is still a lower bound of the score.
+			if self.aborted { break }
+
+			if score <= med {
+				max = score;
+				// Every move fails low, the best one so far is kept otherwise.
+				if best_mov == u8::MAX { best_mov = mov }
+			} else {
+				min = score;
+				best_mov = mov;
+			}
 		}
+
+		// Every probe was cut before trying a move, any move reaching `min` is best.
+		if best_mov == u8::MAX && !self.aborted { best_mov = self.negamax(position, min - 1, min).0 }
+		if best_mov == u8::MAX { best_mov = Solver::first_move(position) }
+
+		(best_mov, min)
+	}
+
+	// Looks `depth` moves ahead and evaluates the positions reached with
+	// `Position::threat_balance`. The result is flagged as approximate
+	// unless the game ended within `depth` moves on every line.
+	pub fn search_depth_position<B: Bits>(&mut self, position: Position<B>, depth: u8) -> SolverResult {
+		self.start(position.rules());
+		if let Some(result) = self.game_over_result(position) { return result }
+		let (mov, score) = self.depth_limited_negamax(position, i8::MIN + 1, i8::MAX - 1, depth);
+		if self.aborted { return self.aborted_result(position, mov, score) }
+		self.result(mov, score)
 	}
 
-	pub fn weakly_solve_str(position: String) -> Result<SolverResult, &'static str> {
-		match Position::try_from(position) {
-			Result::Ok(position) => Ok(Solver::weakly_solve(position)),
-			Result::Err(err) => Err(err)
+	// The transposition table is cleared if `rules` are not the ones of
+	// the previous search.
+	fn start(&mut self, rules: Rules) {
+		self.positions_checked = 0;
+		self.depth_reached = false;
+		self.aborted = false;
+		self.deadline = self.budget.time.map(|time| Instant::now() + time);
+		self.transposition_table.set_rules(rules);
+		self.transposition_table.reset_stats();
+	}
+
+	// Counting nodes is cheap, the clock and the cancellation token are
+	// only checked every few nodes.
+	fn out_of_budget(&mut self) -> bool {
+		if self.aborted { return true }
+
+		self.aborted = self.budget.nodes.is_some_and(|nodes| self.positions_checked > nodes)
+			|| self.positions_checked.is_multiple_of(1024) && (
+				self.deadline.is_some_and(|deadline| Instant::now() >= deadline)
+				|| self.budget.cancel.as_ref().is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+			);
+		self.aborted
+	}
+
+	// `(mov, score)` comes from an interrupted search, the score is only
+	// kept if a move was searched entirely.
+	fn aborted_result<B: Bits>(&self, position: Position<B>, mov: u8, score: i8) -> SolverResult {
+		let lower_bound = -((position.max_moves() - position.move_count) as i8) / 2;
+		if mov == u8::MAX {
+			return self.result(Solver::first_move(position), lower_bound)
 		}
+		self.result(mov, score.max(lower_bound))
+	}
+
+	// Most promising move according to the move ordering.
+	fn first_move<B: Bits>(pos: Position<B>) -> u8 {
+		pos.possible_moves().find(|mov| pos.wins(*mov))
+			.or_else(|| MoveSorter::new(pos, Bitboard::full(pos.size())).next())
+			.unwrap_or(0)
 	}
 
-	fn new() -> Solver { Solver { positions_checked: 0, transposition_table: HashMap::new() } }
+	// The player to move lost if the opponent won with the last move,
+	// there is no best move then.
+	fn game_over_result<B: Bits>(&self, position: Position<B>) -> Option<SolverResult> {
+		match position.status() {
+			Status::Ongoing => None,
+			Status::Draw => Some(self.result(0, 0)),
+			Status::Won(_) => Some(self.result(0, -(position.max_moves() as i8 + 2 - position.move_count as i8) / 2)),
+		}
+	}
 
-	fn weakly_solve_(&mut self, position: Position) -> (u8, i8) {
-		self.negamax(position, -1,  1, 14 + 2 * position.move_count as i8)
+	fn result(&self, best_move: u8, score: i8) -> SolverResult {
+		SolverResult {
+			best_move,
+			positions_checked: self.positions_checked,
+			outcome: score.into(),
+			table_stats: self.transposition_table.stats(),
+			approximate: self.depth_reached || self.aborted,
+			principal_variation: String::new(),
+		}
 	}
 
-	fn strongly_solve(&mut self, position: Position) -> (u8, i8) {
-		self.negamax(position, i8::MIN + 1, i8::MAX - 1, 14 + 2 * position.move_count as i8)
+	// Plays `best_move` then the best moves of each player until the game
+	// ends, see `GridSize::column_name`.
+	fn principal_variation<B: Bits>(&mut self, mut position: Position<B>, best_move: u8) -> String {
+		let mut line = String::new();
+		let mut mov = best_move;
+		while !position.is_terminal() {
+			line.push(position.size().column_name(mov));
+			if position.wins(mov) { break }
+
+			position = position.next(mov);
+			mov = self.exact_negamax(position).0;
+			if self.aborted { return String::new() }
+		}
+		line
 	}
 
-	fn negamax(&mut self, pos: Position, mut alpha: i8, mut beta: i8, depth: i8) -> (u8, i8) {
+	fn negamax<B: Bits>(&mut self, pos: Position<B>, mut alpha: i8, mut beta: i8) -> (u8, i8) {
 		self.positions_checked += 1;
-		// Check for draw, this is ok to do it here, but if given an
-		// already winning position with a full grid, negamax would
-		// still consider it a draw.
+		if self.out_of_budget() { return (u8::MAX, 0) }
+		// Check for draw, positions already won never get here, see
+		// `game_over_result`.
 		if pos.is_terminal() { return (0, 0) }
 
-		// upper bound of the score (if winning, then this is the actual score).
-		let mut position_evaluation = (
-			position::GRID_SIZE.width as i8 * position::GRID_SIZE.height as i8
-			+ 1
-			- pos.move_count as i8
-		) / 2;
-
-		let mut estimate_scores: std::collections::BinaryHeap<MoveScore> = std::collections::BinaryHeap::new();
+		// score when winning with the next move.
+		let win_score = (pos.max_moves() as i8 + 1 - pos.move_count as i8) / 2;
 
-		for mov in pos.possible_moves() {
-			if pos.wins(mov) {
-				return (mov, position_evaluation)
-			}
+		if let Some(mov) = pos.possible_moves().find(|mov| pos.wins(*mov)) {
+			return (mov, win_score)
+		}
 
-			estimate_scores.push(MoveScore(mov, (pos.move_score(mov), [0,1,2,3,2,1,0][mov as usize])))
+		// The opponent wins with their next move whatever we play.
+		let non_losing_moves = pos.possible_non_losing_moves();
+		if non_losing_moves.is_empty() {
+			return (pos.possible_moves().next().unwrap(), -(pos.max_moves() as i8 - pos.move_count as i8) / 2)
 		}
 
-		// Initialized to make sure we compile, however, this will
-		// effectively never be returned as it is.
-		let mut best_mov = u8::MAX;
-		// TODO: it still gives u8::MAX for position
-		// 661444666637315414455515
+		let moves = MoveSorter::new(pos, non_losing_moves);
+
+		if let Some(found) = self.book.as_ref().and_then(|book| book.get(&pos)) { return found }
 
+		// Kept if every move fails low and nothing is cached, as any
+		// non-losing move is as good as the bounds below.
+		let mut best_mov = moves.peek().unwrap();
 
-		// Arbitrary chosen move, it will be changed if
-		// a better move exits.
-		// let mut best_mov = pos.possible_moves().nth(0);
+		// A position and its mirror share their entry, moves are stored
+		// as played in the position with the smallest key.
+		let key = pos.canonical_key();
+		let mirrored = key != pos.key();
+		let orient = |mov: u8| if mirrored && mov != u8::MAX { pos.mirror_column(mov) } else { mov };
 
-		if let Some((cached_best_mov, cached_upper_bound)) = self.transposition_table.get(&pos.key()) {
-			position_evaluation = *cached_upper_bound;
-			best_mov = *cached_best_mov;
+		if let Some((cached_best_mov, cached_score, bound)) = self.transposition_table.get(key) {
+			best_mov = orient(cached_best_mov);
+			match bound {
+				Bound::Exact => return (best_mov, cached_score),
+				Bound::Lower => alpha = alpha.max(cached_score),
+				Bound::Upper => beta = beta.min(cached_score),
+			}
+			if alpha >= beta { return (best_mov, cached_score) } // the cached bound is enough.
 		}
 
-		if beta > position_evaluation {
-			beta = position_evaluation;                  // max possible score anyway.
-			if alpha >= beta { return (best_mov, beta) } // we can prune early, the window is empty.
+		// lower bound of the score, the opponent cannot win with their next move.
+		let lower_bound = -(pos.max_moves() as i8 - 2 - pos.move_count as i8) / 2;
+		if alpha < lower_bound {
+			alpha = lower_bound;
+			if alpha >= beta { return (best_mov, alpha) }
 		}
 
-		// A realy dirty way to go faster in early game. We return a position
-		// estimation that is just: _I think this is a draw_.
-		if depth == 0 {
-			if let Some(MoveScore(mov, _)) = estimate_scores.peek() {
-				return (*mov, 0); // just assume it is a draw.
-			}
+		// upper bound of the score, we cannot win with the next move.
+		let upper_bound = win_score - 1;
+		if beta > upper_bound {
+			beta = upper_bound;                          // max possible score anyway.
+			if alpha >= beta { return (best_mov, beta) } // we can prune early, the window is empty.
 		}
 
+		// Any score above it is exact, any score below is an upper bound.
+		let window_start = alpha;
+		let mut best_score = i8::MIN;
+
 		// Moves in the center are more likely to provide an efficient result, this
 		// heuristic should massively improve our alpha-beta pruning.
-		while let Some(MoveScore(mov, _)) = estimate_scores.pop() {
+		for mov in moves {
 			// Since opponent win condition is the opposite of ours, their
 			// window is [-beta;-alpha].
-			let score = match self.negamax(pos.next(mov), -beta, -alpha, depth - 1) {
-				(_, sc) => -sc
-			};
+			let opponent_score = self.negamax(pos.next(mov), -beta, -alpha).1;
+			// Only the moves searched entirely count, nothing is cached.
+			if self.aborted { return (best_mov, best_score.max(window_start)) }
+			let score = -opponent_score;
+
+			// Prune if we find better than our window, the score is only a lower bound.
+			if score >= beta {
+				self.transposition_table.insert(key, pos.move_count, orient(mov), score, Bound::Lower);
+				return (mov, score)
+			}
 
-			// Prune if we find better than our window.
-			if score >= beta { return (mov, score) }
+			if score > best_score {
+				best_score = score;
+				best_mov = mov;
+			}
 
 			// Reduce the alpha-beta window is possible.
 			// TODO: use a move queue there and pick one at random.
-			if score > alpha {
-				alpha = score;
+			alpha = alpha.max(score);
+		}
+
+		let bound = if best_score > window_start { Bound::Exact } else { Bound::Upper };
+		self.transposition_table.insert(key, pos.move_count, orient(best_mov), best_score, bound);
+		(best_mov, best_score)
+	}
+
+	// Same as `negamax` without the transposition table, which only holds
+	// exact bounds, and with a heuristic evaluation once `depth` is 0.
+	fn depth_limited_negamax<B: Bits>(&mut self, pos: Position<B>, mut alpha: i8, mut beta: i8, depth: u8) -> (u8, i8) {
+		self.positions_checked += 1;
+		if self.out_of_budget() { return (u8::MAX, 0) }
+		if pos.is_terminal() { return (0, 0) }
+
+		let win_score = (pos.max_moves() as i8 + 1 - pos.move_count as i8) / 2;
+
+		if let Some(mov) = pos.possible_moves().find(|mov| pos.wins(*mov)) {
+			return (mov, win_score)
+		}
+		let moves = MoveSorter::new(pos, Bitboard::full(pos.size()));
+
+		let upper_bound = win_score - 1;
+		let lower_bound = -(pos.max_moves() as i8 - pos.move_count as i8) / 2;
+		if depth == 0 {
+			self.depth_reached = true;
+			let best_estimate = moves.peek().unwrap();
+			return (best_estimate, pos.threat_balance().clamp(lower_bound, upper_bound))
+		}
+
+		beta = beta.min(upper_bound);
+		let mut best_mov = u8::MAX;
+		let mut best_score = i8::MIN;
+
+		for mov in moves {
+			let opponent_score = self.depth_limited_negamax(pos.next(mov), -beta, -alpha, depth - 1).1;
+			if self.aborted { return (best_mov, best_score) }
+			let score = -opponent_score;
+
+			if score >= beta { return (mov, score) }
+
+			if score > best_score {
+				best_score = score;
 				best_mov = mov;
 			}
+			alpha = alpha.max(score);
 		}
 
-		self.transposition_table.insert(pos.key(), (best_mov, alpha)); // save the upper bound of the position
-		return (best_mov, alpha)
+		(best_mov, best_score)
 	}
 }
 
+impl Default for Solver {
+	fn default() -> Solver {
+		Solver::new()
+	}
+}
+
+impl Solver {
+	pub fn solve_str(position: String) -> Result<SolverResult, PositionParseError> {
+		Position::try_from(position).map(Solver::solve)
+	}
+
+	pub fn weakly_solve_str(position: String) -> Result<SolverResult, PositionParseError> {
+		Position::try_from(position).map(Solver::weakly_solve)
+	}
+}
+
+#[test]
+fn test_move_sorter() {
+	use crate::position::GridSize;
+
+	let moves = |pos: Position| MoveSorter::new(pos, Bitboard::full(pos.size())).collect::<Vec<u8>>();
+	assert_eq!(moves(Position::new_empty()), [3, 2, 4, 1, 5, 0, 6]);
+	assert_eq!(moves(Position::with_size(GridSize::new(6, 4))), [3, 2, 4, 1, 5, 0]);
+	assert_eq!(moves(Position::with_size(GridSize::new(1, 4))), [0]);
+	// Greater scores first, then the center ones.
+	for moves_played in ["1111113344", "2252576253462244111563365343671351441", "4455"] {
+		let pos = Position::try_from(moves_played).unwrap();
+		let mut expected: Vec<u8> = [3, 2, 4, 1, 5, 0, 6].into_iter().filter(|col| pos.can_play(*col)).collect();
+		expected.sort_by_key(|col| std::cmp::Reverse(pos.move_score(*col)));
+		assert_eq!(moves(pos), expected);
+	}
+
+	let pos = Position::new_empty();
+	let mut sorter = MoveSorter::new(pos, pos.column_mask(2) | pos.column_mask(4));
+	assert_eq!(sorter.peek(), Some(2));
+	sorter.add(0, 1);
+	assert_eq!(sorter.collect::<Vec<u8>>(), [0, 2, 4]);
+}
+
 #[test]
 fn test_solve() {
 	// assert!(matches!(
 	// 	Solver::weakly_solve_str("661444666637315414455515".to_string()),
 	// 	Ok(_)
 	// ));
-	assert!(matches!(
-		Solver::solve_str("661444666637315414455515".to_string()),
-		Ok(_)
-	));
+	assert!(Solver::solve_str("661444666637315414455515".to_string()).is_ok());
 	assert!(
 		matches!(
 			Solver::solve_str("4444233333246".to_string()),
-			Ok((.., Outcome::Win(x))) if x > 5
+			Ok(SolverResult { outcome: Outcome::Win(2), .. })
 		),
 		"Got {:?}", Solver::solve_str("4444233333246".to_string())
 	);
-	assert!(matches!(Solver::solve_str("23163416124767223154467471272416755633".to_string()), Ok((.., Outcome::Draw))));
+	assert!(matches!(Solver::solve_str("23163416124767223154467471272416755633".to_string()), Ok(SolverResult { outcome: Outcome::Draw, .. })));
+}
+
+#[test]
+fn test_solve_exact() {
+	for moves in [
+		"2252576253462244111563365343671351441",
+		"7422341735647741166133573473242566",
+		"5554224333234511764415115",
+		"52753311433677442422121",
+		"4444233333246",
+	] {
+		let pos = Position::try_from(moves).unwrap();
+		let full_window = Solver::solve(pos);
+		let null_window = Solver::solve_exact(pos);
+		assert_eq!(full_window.outcome, null_window.outcome, "{}", moves);
+		assert_eq!(
+			Solver::solve(pos.next(null_window.best_move)).outcome,
+			Solver::solve(pos.next(full_window.best_move)).outcome,
+			"{}", moves
+		);
+	}
+}
+
+#[test]
+fn test_other_sizes() {
+	use crate::position::GridSize;
+
+	assert!(matches!(Solver::solve(Position::<u64>::with_size(GridSize::new(4, 4))), SolverResult { outcome: Outcome::Draw, .. }));
+	assert!(matches!(
+		Solver::solve(Position::<u64>::from_moves(GridSize::new(6, 5), "3344").unwrap()),
+		SolverResult { best_move: 1 | 4, outcome: Outcome::Win(_), .. }
+	));
+	assert!(matches!(
+		Solver::solve(Position::<u128>::from_moves(GridSize::new(9, 7), "445566").unwrap()),
+		SolverResult { best_move: 2 | 6, outcome: Outcome::Win(_), .. }
+	));
+}
+
+#[test]
+fn test_connect_k() {
+	use crate::position::{GridSize, Rules};
+
+	let solve = |width, height, alignment|
+		Solver::solve(Position::<u64>::with_rules(Rules::new(GridSize::new(width, height), alignment))).outcome;
+
+	assert_eq!(solve(3, 2, 2), Outcome::Win(2));
+	assert_eq!(solve(3, 3, 3), Outcome::Draw);
+	assert_eq!(solve(4, 3, 3), Outcome::Win(2));
+	assert_eq!(solve(4, 4, 3), Outcome::Win(4));
+	assert_eq!(solve(5, 4, 3), Outcome::Win(6));
+	assert_eq!(solve(5, 4, 5), Outcome::Draw);
+
+	// The positions cached for other rules are forgotten.
+	let mut solver = Solver::with_table_size(1 << 20);
+	let size = GridSize::new(4, 4);
+	assert_eq!(solver.solve_position(Position::<u64>::with_rules(Rules::new(size, 3))).outcome, Outcome::Win(4));
+	assert_eq!(solver.solve_position(Position::<u64>::with_rules(Rules::new(size, 4))).outcome, Outcome::Draw);
+}
+
+#[test]
+fn test_outcome_score() {
+	for score in [-21, -1, 0, 1, 18] {
+		assert_eq!(i8::from(&Outcome::from(score)), score);
+	}
+}
+
+#[test]
+fn test_game_over() {
+	// The first player just won, with their fourth piece.
+	let won = Position::try_from("121212").unwrap().next(0);
+	let result = Solver::solve(won);
+	assert_eq!(result.outcome, Outcome::Loose(18));
+	assert_eq!(Solver::explain_outcome(won, &result), "loose in 0 moves");
+	assert_eq!(Solver::solve_exact(won).outcome, Outcome::Loose(18));
+	assert_eq!(Solver::new().solve_parallel_position(won, 2).outcome, Outcome::Loose(18));
+	assert_eq!(Solver::analyze(won), vec![None; 7]);
+}
+
+#[test]
+fn test_mirror() {
+	for moves in ["2252576253462244111563365343671351441", "5554224333234511764415115", "1233722555341451114725221333"] {
+		let pos = Position::try_from(moves).unwrap();
+		assert_eq!(Solver::solve(pos).outcome, Solver::solve(pos.mirror()).outcome);
+	}
+}
+
+#[test]
+fn test_analyze() {
+	let pos = Position::try_from("111111525252").unwrap();
+	let scores = Solver::analyze(pos);
+	assert_eq!(scores.len(), 7);
+	assert_eq!(scores[0], None);
+	assert_eq!(scores[4], Some(15), "5 wins right away");
+	assert!(scores.iter().flatten().all(|score| *score <= 15));
+
+	for moves in ["5554224333234511764415115", "1233722555341451114725221333", "7422341735647741166133573473242566"] {
+		let pos = Position::try_from(moves).unwrap();
+		let scores = Solver::analyze(pos);
+		let result = Solver::solve(pos);
+		assert_eq!(Outcome::from(scores.iter().flatten().copied().max().unwrap()), result.outcome);
+		assert_eq!(Outcome::from(scores[result.best_move as usize].unwrap()), result.outcome);
+	}
+}
+
+#[test]
+fn test_principal_variation() {
+	for moves in ["2252576253462244111563365343671351441", "52753311433677442422121", "23163416124767223154467471272416755633", "4444233333246"] {
+		let pos = Position::try_from(moves).unwrap();
+		for result in [Solver::solve(pos), Solver::solve_exact(pos)] {
+			let line = &result.principal_variation;
+			assert!(line.starts_with(&(result.best_move + 1).to_string()));
+
+			let end = Position::try_from(format!("{}{}", moves, &line[..line.len() - 1])).unwrap();
+			let last_move = line.chars().last().unwrap().to_digit(10).unwrap() as u8 - 1;
+			match result.outcome {
+				Outcome::Draw => assert!(end.next(last_move).is_terminal()),
+				Outcome::Win(_) => assert!(end.wins(last_move) && line.len() % 2 == 1),
+				Outcome::Loose(_) => assert!(end.wins(last_move) && line.len() % 2 == 0),
+			}
+			assert_eq!(
+				Solver::explain_outcome(pos, &result),
+				format!("{}: {}", Solver::explain_outcome(pos, &SolverResult { principal_variation: String::new(), ..result }), line),
+			);
+		}
+	}
+}
+
+#[test]
+fn test_small_table() {
+	// Tables too small to store 32 bits keys.
+	for bytes in [1 << 20, 4 << 10] {
+		let mut solver = Solver::with_table_size(bytes);
+		for (moves, score) in [("24114657447556133315577775", -1), ("7422341735647741166133573473242566", 1)] {
+			let result = solver.solve_position(Position::try_from(moves).unwrap());
+			assert_eq!(i8::from(&result.outcome), score, "{} with {} bytes", moves, bytes);
+		}
+	}
+}
+
+#[test]
+fn test_save_table() {
+	use crate::position::{GridSize, CONNECT_FOUR};
+
+	let path = std::env::temp_dir().join(format!("connect-four-solver-table-{}", std::process::id()));
+	let rules = Rules::new(GridSize::new(4, 4), 3);
+	let mut solver = Solver::with_table_size(1 << 16);
+	solver.solve_position(Position::<u64>::with_rules(rules));
+	// Saved for the rules of the positions solved.
+	solver.save_table(&path).unwrap();
+	assert!(solver.load_table(&path, CONNECT_FOUR).is_err());
+	solver.load_table(&path, rules).unwrap();
+	std::fs::remove_file(&path).unwrap();
+	assert_eq!(solver.solve_position(Position::<u64>::with_rules(rules)).outcome, Outcome::Win(4));
+}
+
+#[test]
+fn test_solve_parallel() {
+	let mut solver = Solver::with_table_size(1 << 20);
+	for moves in ["2252576253462244111563365343671351441", "5554224333234511764415115", "4444233333246", "111111525252"] {
+		let pos = Position::try_from(moves).unwrap();
+		let expected = Solver::solve(pos);
+		for threads in [1, 3] {
+			let result = solver.solve_parallel_position(pos, threads);
+			assert_eq!(result.outcome, expected.outcome, "{} with {} threads", moves, threads);
+			assert!(result.principal_variation.starts_with(&(result.best_move + 1).to_string()));
+			assert_eq!(
+				Solver::solve(pos.next(result.best_move)).outcome,
+				Solver::solve(pos.next(expected.best_move)).outcome,
+			);
+		}
+	}
+
+	solver.set_budget(Budget { nodes: Some(100), ..Budget::default() });
+	let pos = Position::try_from("4453").unwrap();
+	for threads in [1, 2, 3] {
+		let result = solver.solve_parallel_position(pos, threads);
+		assert!(result.approximate && pos.can_play(result.best_move));
+		assert!(result.positions_checked <= 100 + threads as u128, "{} with {} threads", result.positions_checked, threads);
+	}
+}
+
+#[test]
+fn test_book() {
+	use crate::position::{GridSize, Rules};
+
+	let rules = Rules::new(GridSize::new(5, 4), 3);
+	let mut solver = Solver::with_table_size(1 << 20);
+	let book = solver.build_book::<u64>(rules, 3);
+	assert_eq!(book.depth(), 3);
+	assert!(!book.is_empty());
+
+	let path = std::env::temp_dir().join(format!("connect-four-solver-book-{}", std::process::id()));
+	book.save(&path).unwrap();
+	let mut with_book = Solver::with_book(&path).unwrap();
+	std::fs::remove_file(&path).unwrap();
+
+	for moves in ["", "3", "32", "11", "123", "5432", "15243"] {
+		let pos = Position::<u64>::with_rules(rules).play_moves(moves).unwrap();
+		let expected = Solver::solve(pos);
+		let result = with_book.solve_position(pos);
+		assert_eq!(result.outcome, expected.outcome, "{}", moves);
+		if pos.move_count <= 3 { assert_eq!(result.positions_checked, 1, "{}", moves) }
+		assert_eq!(Solver::solve(pos.next(result.best_move)).outcome, Solver::solve(pos.next(expected.best_move)).outcome);
+	}
+}
+
+#[test]
+fn test_budget() {
+	let pos = Position::try_from("4453").unwrap();
+	let mut solver = Solver::with_table_size(1 << 20);
+
+	solver.set_budget(Budget { nodes: Some(10_000), ..Budget::default() });
+	for result in [solver.solve_position(pos), solver.solve_exact_position(pos), solver.weakly_solve_position(pos)] {
+		assert!(result.approximate);
+		assert!(pos.can_play(result.best_move));
+		assert_eq!(result.positions_checked, 10_001);
+		assert!(result.principal_variation.is_empty());
+	}
+	assert!(solver.analyze_position(pos).iter().any(Option::is_none));
+
+	let cancel = Arc::new(AtomicBool::new(true));
+	solver.set_budget(Budget { cancel: Some(cancel.clone()), ..Budget::default() });
+	let result = solver.solve_position(pos);
+	assert!(result.approximate && result.positions_checked <= 1024);
+
+	solver.set_budget(Budget { time: Some(Duration::ZERO), ..Budget::default() });
+	let result = solver.search_depth_position(pos, 20);
+	assert!(result.approximate && result.positions_checked <= 1024);
+
+	// Nothing cached by interrupted searches is wrong.
+	let pos = Position::try_from("2252576253462").unwrap();
+	solver.set_budget(Budget { nodes: Some(1_000), ..Budget::default() });
+	assert!(solver.solve_position(pos).approximate);
+	solver.set_budget(Budget::default());
+	let result = solver.solve_position(pos);
+	assert!(!result.approximate);
+	assert_eq!(result.outcome, Solver::solve(pos).outcome);
+}
+
+#[test]
+fn test_search_depth() {
+	let result = Solver::search_depth(Position::new_empty(), 6);
+	assert!(result.approximate);
+	assert!(result.best_move < 7);
+
+	// Deep enough to reach the end of the game.
+	let pos = Position::try_from("2252576253462244111563365343671351441").unwrap();
+	let result = Solver::search_depth(pos, 6);
+	assert!(!result.approximate);
+	assert_eq!(result.outcome, Solver::solve(pos).outcome);
+
+	let pos = Position::try_from("23163416124767223154467471272416755633").unwrap();
+	let result = Solver::search_depth(pos, 2);
+	assert!(result.approximate);
+	assert_eq!(Solver::search_depth(pos, 4).outcome, Outcome::Draw);
 }
 
 #[test]
+#[ignore = "weakly solving the empty grid is too long for a unit test"]
 fn test_from_beginning() {
-	assert!(matches!(Solver::weakly_solve_str("".to_string()), Ok((3, _, Outcome::Win(_)))));
+	assert!(matches!(Solver::weakly_solve_str("".to_string()), Ok(SolverResult { best_move: 3, outcome: Outcome::Win(_), .. })));
 }
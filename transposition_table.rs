@@ -0,0 +1,437 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::bitboard::Bits;
+use crate::position::Rules;
+
+/// Memory used by default by a solver's transposition table.
+pub const DEFAULT_TABLE_SIZE: usize = 64 << 20;
+
+const MAGIC: &[u8; 4] = b"C4TT";
+const VERSION: u8 = 2;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TableStats {
+	pub hits: u64,
+	pub misses: u64,
+	// Entries overwritten by a different position.
+	pub collisions: u64,
+}
+
+/// What a cached value tells about the actual score of a position.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bound {
+	Exact,
+	// The score is at least the value.
+	Lower,
+	// The score is at most the value.
+	Upper,
+}
+
+// What a table knows of a position, apart from its key.
+#[derive(Debug, Clone, Copy)]
+struct Cached {
+	move_count: u8,
+	best_move: u8,
+	value: i8,
+	bound: Bound,
+}
+
+// An entry stores the quotient of its key by the number of buckets, the
+// remainder being the index of its bucket.
+trait Entry: Copy {
+	const KEY_BYTES: usize;
+
+	fn new(quotient: u128, cached: Cached) -> Self;
+	fn key(&self) -> u128;
+	fn cached(&self) -> Cached;
+	fn write_key(&self, bytes: &mut [u8]);
+	fn read_key(bytes: &[u8]) -> u128;
+}
+
+// One type per key size rather than a generic one, whose tables take
+// much longer to fill in debug builds.
+macro_rules! entries {
+	($($entry:ident: $key:ty),*) => {$(
+		#[derive(Debug, Clone, Copy)]
+		struct $entry {
+			key: $key,
+			cached: Cached,
+		}
+
+		impl Entry for $entry {
+			const KEY_BYTES: usize = std::mem::size_of::<$key>();
+
+			fn new(quotient: u128, cached: Cached) -> Self { $entry { key: quotient as $key, cached } }
+			fn key(&self) -> u128 { self.key as u128 }
+			fn cached(&self) -> Cached { self.cached }
+			fn write_key(&self, bytes: &mut [u8]) { bytes.copy_from_slice(&self.key.to_le_bytes()) }
+			fn read_key(bytes: &[u8]) -> u128 { <$key>::from_le_bytes(bytes.try_into().unwrap()) as u128 }
+		}
+	)*}
+}
+
+entries!(NarrowEntry: u32, WideEntry: u128);
+
+// Each key maps to a bucket of two slots. The first one keeps the entry
+// closest to the root, as it saved the most work, the second one always
+// keeps the last entry.
+type Bucket<E> = [Option<E>; 2];
+
+// Quotients are stored in 32 bits when they all fit, so that a bucket
+// takes 16 bytes, and whole otherwise.
+enum Buckets {
+	Narrow(Vec<Bucket<NarrowEntry>>),
+	Wide(Vec<Bucket<WideEntry>>),
+}
+
+/// Fixed-size cache of solved positions.
+///
+/// Two keys sharing a bucket have different quotients by the number of
+/// buckets, so entries never match another position. These quotients fit
+/// in 32 bits for keys of `n` bits as soon as there are `2^(n - 32)`
+/// buckets, e.g. 2^17 for a 7x6 grid (49 bits keys), which takes 2MB.
+/// Smaller tables and larger grids need entries four times larger.
+pub struct TranspositionTable {
+	// Keys of other rules may be equal, see `set_rules`.
+	rules: Rules,
+	// Memory allowed, the number of buckets depends on the rules.
+	max_memory: usize,
+	buckets: Buckets,
+	stats: TableStats,
+}
+
+impl TranspositionTable {
+	// Largest table fitting in `bytes`, but never less than two buckets.
+	pub fn with_memory(rules: Rules, bytes: usize) -> TranspositionTable {
+		let narrow = largest_prime_below((bytes / std::mem::size_of::<Bucket<NarrowEntry>>()).max(2));
+		let buckets = if fits_narrow(rules, narrow) {
+			Buckets::Narrow(vec![[None; 2]; narrow])
+		} else {
+			Buckets::Wide(vec![[None; 2]; largest_prime_below((bytes / std::mem::size_of::<Bucket<WideEntry>>()).max(2))])
+		};
+		TranspositionTable { rules, max_memory: bytes, buckets, stats: TableStats::default() }
+	}
+
+	// Clears the table before it caches positions of other rules.
+	pub fn set_rules(&mut self, rules: Rules) {
+		if rules != self.rules {
+			*self = TranspositionTable::with_memory(rules, self.max_memory);
+		}
+	}
+
+	#[cfg(test)]
+	pub fn capacity(&self) -> usize {
+		self.len() * 2
+	}
+
+	pub fn memory(&self) -> usize {
+		match &self.buckets {
+			Buckets::Narrow(buckets) => std::mem::size_of_val(buckets.as_slice()),
+			Buckets::Wide(buckets) => std::mem::size_of_val(buckets.as_slice()),
+		}
+	}
+
+	pub fn stats(&self) -> TableStats {
+		self.stats
+	}
+
+	pub fn reset_stats(&mut self) {
+		self.stats = TableStats::default();
+	}
+
+	pub fn clear(&mut self) {
+		match &mut self.buckets {
+			Buckets::Narrow(buckets) => buckets.fill([None; 2]),
+			Buckets::Wide(buckets) => buckets.fill([None; 2]),
+		}
+		self.reset_stats();
+	}
+
+	// Cached `(best_move, value, bound)` for a position key.
+	pub fn get<B: Bits>(&mut self, key: B) -> Option<(u8, i8, Bound)> {
+		let (quotient, index) = key.div_rem(self.len() as u64);
+		let found = match &self.buckets {
+			Buckets::Narrow(buckets) => find(&buckets[index as usize], quotient),
+			Buckets::Wide(buckets) => find(&buckets[index as usize], quotient),
+		};
+		match found {
+			Some(_) => self.stats.hits += 1,
+			None => self.stats.misses += 1,
+		}
+		found
+	}
+
+	// `move_count` is the number of moves played in the position, entries
+	// with a lower one are harder to replace.
+	pub fn insert<B: Bits>(&mut self, key: B, move_count: u8, best_move: u8, value: i8, bound: Bound) {
+		let (quotient, index) = key.div_rem(self.len() as u64);
+		let cached = Cached { move_count, best_move, value, bound };
+		let collision = match &mut self.buckets {
+			Buckets::Narrow(buckets) => replace(&mut buckets[index as usize], quotient, cached),
+			Buckets::Wide(buckets) => replace(&mut buckets[index as usize], quotient, cached),
+		};
+		if collision { self.stats.collisions += 1 }
+	}
+
+	// Keys only make sense for positions of the same rules, they are
+	// written in the header along with the number of buckets. Each slot
+	// takes 4 bytes more than a stored key, an empty one has a zero bound.
+	pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+		let rules = self.rules;
+		let mut file = BufWriter::new(File::create(path)?);
+		file.write_all(MAGIC)?;
+		file.write_all(&[VERSION, rules.size.width, rules.size.height, rules.alignment])?;
+		file.write_all(&(self.len() as u64).to_le_bytes())?;
+		match &self.buckets {
+			Buckets::Narrow(buckets) => write_buckets(&mut file, buckets)?,
+			Buckets::Wide(buckets) => write_buckets(&mut file, buckets)?,
+		}
+		file.flush()
+	}
+
+	// Fails if the file was not saved for the same `rules`.
+	pub fn load<P: AsRef<Path>>(path: P, rules: Rules) -> io::Result<TranspositionTable> {
+		let file = File::open(path)?;
+		let length = file.metadata()?.len();
+		let mut file = BufReader::new(file);
+
+		let mut header = [0u8; 16];
+		file.read_exact(&mut header[..5])?;
+		if &header[..4] != MAGIC { return Err(invalid("not a transposition table")) }
+		if header[4] != VERSION { return Err(invalid("unsupported transposition table version")) }
+		file.read_exact(&mut header[5..])?;
+		if header[5..8] != [rules.size.width, rules.size.height, rules.alignment] {
+			return Err(invalid("transposition table saved for other rules"))
+		}
+		let buckets = u64::from_le_bytes(header[8..].try_into().unwrap()) as usize;
+		// Checked against the file before anything slow, such as allocating
+		// the table or telling whether the size is prime.
+		let narrow = fits_narrow(rules, buckets);
+		let slot = 4 + if narrow { NarrowEntry::KEY_BYTES } else { WideEntry::KEY_BYTES };
+		if (length as u128).checked_sub(16) != Some(buckets as u128 * 2 * slot as u128) {
+			return Err(invalid("invalid transposition table size"))
+		}
+		if buckets < 2 || !is_prime(buckets) { return Err(invalid("invalid transposition table size")) }
+
+		let buckets = if narrow {
+			Buckets::Narrow(read_buckets(&mut file, buckets)?)
+		} else {
+			Buckets::Wide(read_buckets(&mut file, buckets)?)
+		};
+		let mut table = TranspositionTable { rules, max_memory: 0, buckets, stats: TableStats::default() };
+		table.max_memory = table.memory();
+		Ok(table)
+	}
+
+	fn len(&self) -> usize {
+		match &self.buckets {
+			Buckets::Narrow(buckets) => buckets.len(),
+			Buckets::Wide(buckets) => buckets.len(),
+		}
+	}
+}
+
+// Whether the quotients of the keys of `rules` by `buckets` fit in 32 bits.
+fn fits_narrow(rules: Rules, buckets: usize) -> bool {
+	buckets as u128 >= 1 << rules.size.bits().saturating_sub(32)
+}
+
+fn find<E: Entry>(bucket: &Bucket<E>, quotient: u128) -> Option<(u8, i8, Bound)> {
+	bucket.iter().flatten()
+		.find(|entry| entry.key() == quotient)
+		.map(|entry| (entry.cached().best_move, entry.cached().value, entry.cached().bound))
+}
+
+// Returns whether an entry of another position was overwritten.
+fn replace<E: Entry>(bucket: &mut Bucket<E>, quotient: u128, cached: Cached) -> bool {
+	let slot = match bucket[0] {
+		None => 0,
+		Some(kept) if kept.key() == quotient || cached.move_count <= kept.cached().move_count => 0,
+		_ => 1,
+	};
+	let collision = matches!(bucket[slot], Some(replaced) if replaced.key() != quotient);
+	bucket[slot] = Some(E::new(quotient, cached));
+	collision
+}
+
+fn write_buckets<E: Entry, W: Write>(file: &mut W, buckets: &[Bucket<E>]) -> io::Result<()> {
+	let mut bytes = [0u8; 20];
+	let bytes = &mut bytes[..E::KEY_BYTES + 4];
+	for slot in buckets.iter().flatten() {
+		bytes.fill(0);
+		if let Some(entry) = slot {
+			entry.write_key(&mut bytes[..E::KEY_BYTES]);
+			let Cached { move_count, best_move, value, bound } = entry.cached();
+			let bound = match bound { Bound::Exact => 1, Bound::Lower => 2, Bound::Upper => 3 };
+			bytes[E::KEY_BYTES..].copy_from_slice(&[move_count, best_move, value as u8, bound]);
+		}
+		file.write_all(bytes)?;
+	}
+	Ok(())
+}
+
+fn read_buckets<E: Entry, R: Read>(file: &mut R, buckets: usize) -> io::Result<Vec<Bucket<E>>> {
+	let slot = |bytes: &[u8]| -> io::Result<Option<E>> {
+		let (key, cached) = bytes.split_at(E::KEY_BYTES);
+		let bound = match cached[3] {
+			0 => return Ok(None),
+			1 => Bound::Exact,
+			2 => Bound::Lower,
+			3 => Bound::Upper,
+			_ => return Err(invalid("invalid transposition table entry")),
+		};
+		let cached = Cached { move_count: cached[0], best_move: cached[1], value: cached[2] as i8, bound };
+		Ok(Some(E::new(E::read_key(key), cached)))
+	};
+
+	let mut table = Vec::with_capacity(buckets);
+	let mut bytes = [0u8; 40];
+	let bytes = &mut bytes[..2 * (E::KEY_BYTES + 4)];
+	for _ in 0..buckets {
+		file.read_exact(bytes)?;
+		let (first, second) = bytes.split_at(E::KEY_BYTES + 4);
+		table.push([slot(first)?, slot(second)?]);
+	}
+	Ok(table)
+}
+
+fn invalid(message: &str) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+const fn is_prime(n: usize) -> bool {
+	if n < 2 { return false }
+	let mut d = 2;
+	while d <= n / d {
+		if n.is_multiple_of(d) { return false }
+		d += 1;
+	}
+	true
+}
+
+const fn largest_prime_below(mut n: usize) -> usize {
+	while !is_prime(n) { n -= 1 }
+	n
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::position::{GridSize, CONNECT_FOUR};
+
+	#[test]
+	fn primes() {
+		assert_eq!(largest_prime_below(2), 2);
+		assert_eq!(largest_prime_below(100), 97);
+		assert_eq!(largest_prime_below((1 << 23) + 9), (1 << 23) + 9);
+	}
+
+	#[test]
+	fn get_and_insert() {
+		let mut table = TranspositionTable::with_memory(CONNECT_FOUR, 1 << 10);
+		assert_eq!(table.get(42u64), None);
+
+		table.insert(42u64, 10, 3, -2, Bound::Exact);
+		assert_eq!(table.get(42u64), Some((3, -2, Bound::Exact)));
+		table.insert(42u64, 10, 4, 1, Bound::Lower);
+		assert_eq!(table.get(42u64), Some((4, 1, Bound::Lower)));
+
+		assert_eq!(table.stats(), TableStats { hits: 2, misses: 1, collisions: 0 });
+
+		table.clear();
+		assert_eq!(table.get(42u64), None);
+	}
+
+	#[test]
+	fn replacement() {
+		let mut table = TranspositionTable::with_memory(CONNECT_FOUR, 1 << 10);
+		let buckets = (table.capacity() / 2) as u64;
+		let [a, b, c] = [1, 1 + buckets, 1 + 2 * buckets];
+
+		table.insert(a, 5, 0, 0, Bound::Exact);
+		table.insert(b, 20, 1, 0, Bound::Exact);
+		table.insert(c, 30, 2, 0, Bound::Exact);
+		// `a` is closer to the root, `c` is the latest.
+		assert_eq!(table.get(a), Some((0, 0, Bound::Exact)));
+		assert_eq!(table.get(b), None);
+		assert_eq!(table.get(c), Some((2, 0, Bound::Exact)));
+		assert_eq!(table.stats().collisions, 1);
+
+		table.insert(b, 2, 1, 0, Bound::Exact);
+		assert_eq!(table.get(a), None);
+		assert_eq!(table.get(b), Some((1, 0, Bound::Exact)));
+	}
+
+	#[test]
+	fn save_and_load() {
+		let path = std::env::temp_dir().join(format!("connect-four-table-{}", std::process::id()));
+		let mut table = TranspositionTable::with_memory(CONNECT_FOUR, 1 << 10);
+		let buckets = (table.capacity() / 2) as u64;
+		table.insert(42u64, 10, 3, -2, Bound::Exact);
+		table.insert(7u64, 5, 1, 4, Bound::Lower);
+		table.insert(7 + buckets, 8, 0, -1, Bound::Upper);
+		table.save(&path).unwrap();
+		// Slots of 16 bytes keys.
+		assert_eq!(std::fs::metadata(&path).unwrap().len(), 16 + table.capacity() as u64 * 20);
+
+		let mut loaded = TranspositionTable::load(&path, CONNECT_FOUR).unwrap();
+		assert_eq!(loaded.capacity(), table.capacity());
+		assert_eq!(loaded.get(42u64), Some((3, -2, Bound::Exact)));
+		assert_eq!(loaded.get(7u64), Some((1, 4, Bound::Lower)));
+		assert_eq!(loaded.get(7 + buckets), Some((0, -1, Bound::Upper)));
+		assert_eq!(loaded.get(8u64), None);
+
+		let other_rules = Rules::new(CONNECT_FOUR.size, 5);
+		assert_eq!(TranspositionTable::load(&path, other_rules).err().unwrap().kind(), io::ErrorKind::InvalidData);
+		let small_grid = Rules::new(GridSize::new(4, 4), 4);
+		let mut table = TranspositionTable::with_memory(small_grid, 1 << 10);
+		table.insert(42u64, 10, 3, -2, Bound::Exact);
+		table.save(&path).unwrap();
+		assert_eq!(std::fs::metadata(&path).unwrap().len(), 16 + table.memory() as u64);
+		assert_eq!(TranspositionTable::load(&path, small_grid).unwrap().get(42u64), Some((3, -2, Bound::Exact)));
+
+		// Sizes not matching the file, large primes included.
+		for buckets in [1099511627791u64, (1 << 61) - 1, 18446744073709551557] {
+			let mut header = b"C4TT\x02\x07\x06\x04".to_vec();
+			header.extend(buckets.to_le_bytes());
+			std::fs::write(&path, &header).unwrap();
+			assert_eq!(TranspositionTable::load(&path, CONNECT_FOUR).err().unwrap().kind(), io::ErrorKind::InvalidData);
+		}
+		std::fs::write(&path, b"C4TT\x01").unwrap();
+		assert_eq!(TranspositionTable::load(&path, CONNECT_FOUR).err().unwrap().kind(), io::ErrorKind::InvalidData);
+		std::fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn memory() {
+		let table = TranspositionTable::with_memory(CONNECT_FOUR, DEFAULT_TABLE_SIZE);
+		assert!(table.memory() <= DEFAULT_TABLE_SIZE);
+		assert!(table.memory() > DEFAULT_TABLE_SIZE / 100 * 99);
+		assert_eq!(std::mem::size_of::<Bucket<NarrowEntry>>(), 16);
+		assert_eq!(std::mem::size_of::<Bucket<WideEntry>>(), 64);
+		// Large enough for 32 bits 7x6 keys.
+		assert!(matches!(table.buckets, Buckets::Narrow(_)));
+		assert!(matches!(TranspositionTable::with_memory(CONNECT_FOUR, 1 << 20).buckets, Buckets::Wide(_)));
+		let small_grid = Rules::new(GridSize::new(4, 4), 4);
+		assert!(matches!(TranspositionTable::with_memory(small_grid, 1 << 10).buckets, Buckets::Narrow(_)));
+	}
+
+	#[test]
+	fn no_false_hits() {
+		// Same bucket and same low 32 bits.
+		let mut table = TranspositionTable::with_memory(CONNECT_FOUR, 1 << 10);
+		let buckets = (table.capacity() / 2) as u64;
+		table.insert(5u64, 10, 3, -2, Bound::Exact);
+		assert_eq!(table.get(5 + (buckets << 32)), None);
+
+		let rules = Rules::new(GridSize::new(10, 10), 4);
+		let mut table = TranspositionTable::with_memory(rules, 1 << 10);
+		let buckets = (table.capacity() / 2) as u128;
+		table.insert(5u128, 10, 3, -2, Bound::Exact);
+		assert_eq!(table.get(5 + (buckets << 32)), None);
+		assert_eq!(table.get(5 + (buckets << 64)), None);
+		assert_eq!(table.get(5u128), Some((3, -2, Bound::Exact)));
+	}
+}